@@ -1,13 +1,15 @@
+//! Accepts connections on port 5900 of an interface on `tun0` and waits for
+//! each to close, see `run.sh`.
+
 use std::io::Read;
 
-mod stream;
-mod tcp;
+use user_space_tcp::Interface;
 
 fn main() {
-    let mut tcp_interface = stream::Interface::default();
+    let mut tcp_interface = Interface::default();
     let listener = tcp_interface
         .bind(5900)
-        .expect("Failed to bind to a port 4600 for our user space tcp interface");
+        .expect("Failed to bind to port 5900 of our user space tcp interface");
     std::thread::spawn(move || {
         while let Ok(mut stream) = listener.try_accept() {
             eprintln!("Accepted a connection");
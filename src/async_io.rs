@@ -0,0 +1,269 @@
+//! Async versions of [`TcpStream`] and [`TcpListener`].
+//!
+//! Instead of parking a thread on the connection's condvars, a pending
+//! operation registers the task's waker and `packet_loop` wakes it when the
+//! segment it was waiting for arrives. With the `tokio` feature the stream
+//! implements tokio's `AsyncRead`/`AsyncWrite`, with `futures-io` the ones
+//! from `futures-io`; both can be enabled at the same time.
+
+use std::{
+    future, io,
+    net::{Shutdown, SocketAddrV4},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::stream::{Interface, TcpListener, TcpStream};
+
+pub struct AsyncTcpStream(TcpStream);
+
+impl AsyncTcpStream {
+    /// Opens a connection to `addr`, resolving once the handshake completes.
+    pub async fn connect(interface: &mut Interface, addr: SocketAddrV4) -> io::Result<Self> {
+        let stream = interface.start_connect(addr)?;
+        future::poll_fn(|cx| stream.poll_connect(cx)).await?;
+        Ok(AsyncTcpStream(stream))
+    }
+
+    /// The underlying stream, e.g. to set socket options.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> TcpStream {
+        self.0
+    }
+
+    pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.0.poll_read(cx, buf)
+    }
+
+    pub fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.0.poll_write(cx, buf)
+    }
+
+    /// Resolves once the peer acknowledged everything written so far.
+    pub fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.poll_flush(cx)
+    }
+
+    /// Sends our FIN after the queued data, reading keeps working.
+    pub fn poll_shutdown(&self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.shutdown(Shutdown::Write))
+    }
+}
+
+impl From<TcpStream> for AsyncTcpStream {
+    fn from(stream: TcpStream) -> Self {
+        AsyncTcpStream(stream)
+    }
+}
+
+pub struct AsyncTcpListener(TcpListener);
+
+impl AsyncTcpListener {
+    pub async fn accept(&self) -> io::Result<AsyncTcpStream> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<AsyncTcpStream>> {
+        self.0.poll_accept(cx).map_ok(AsyncTcpStream)
+    }
+
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> TcpListener {
+        self.0
+    }
+}
+
+impl From<TcpListener> for AsyncTcpListener {
+    fn from(listener: TcpListener) -> Self {
+        AsyncTcpListener(listener)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = match AsyncTcpStream::poll_read(&self, cx, buf.initialize_unfilled()) {
+            Poll::Ready(Ok(n)) => n,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncTcpStream::poll_write(&self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncTcpStream::poll_flush(&self, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncTcpStream::poll_shutdown(&self, cx)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncTcpStream::poll_read(&self, cx, buf)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncTcpStream::poll_write(&self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncTcpStream::poll_flush(&self, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncTcpStream::poll_shutdown(&self, cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // More than fits in the send buffer, so that writes have to wait for
+    // the reader.
+    const REQUEST_LEN: usize = 64 << 10;
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_echo() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = AsyncTcpListener::from(server.bind(80).unwrap());
+        let addr = SocketAddrV4::new(server.addr(), 80);
+        let (stream, accepted) = tokio::join!(
+            AsyncTcpStream::connect(&mut client, addr),
+            listener.accept()
+        );
+        let (mut stream, mut accepted) = (stream.unwrap(), accepted.unwrap());
+
+        let request: Vec<u8> = (0..REQUEST_LEN).map(|i| i as u8).collect();
+        let mut received = Vec::new();
+        let (sent, read) = tokio::join!(
+            async {
+                stream.write_all(&request).await?;
+                stream.flush().await?;
+                stream.shutdown().await
+            },
+            accepted.read_to_end(&mut received),
+        );
+        sent.unwrap();
+        assert_eq!(read.unwrap(), REQUEST_LEN);
+        assert_eq!(received, request);
+
+        accepted.write_all(b"response").await.unwrap();
+        accepted.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[test]
+    fn futures_io_echo() {
+        use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = AsyncTcpListener::from(server.bind(80).unwrap());
+        let addr = SocketAddrV4::new(server.addr(), 80);
+        futures::executor::block_on(async {
+            let (stream, accepted) = futures::join!(
+                AsyncTcpStream::connect(&mut client, addr),
+                listener.accept()
+            );
+            let (mut stream, mut accepted) = (stream.unwrap(), accepted.unwrap());
+
+            let request: Vec<u8> = (0..REQUEST_LEN).map(|i| i as u8).collect();
+            let mut received = Vec::new();
+            let (sent, read) = futures::join!(
+                async {
+                    stream.write_all(&request).await?;
+                    stream.flush().await?;
+                    stream.close().await
+                },
+                accepted.read_to_end(&mut received),
+            );
+            sent.unwrap();
+            assert_eq!(read.unwrap(), REQUEST_LEN);
+            assert_eq!(received, request);
+
+            accepted.write_all(b"response").await.unwrap();
+            accepted.close().await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"response");
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn connect_refused() {
+        let (mut client, server) = Interface::loopback_pair();
+        let addr = SocketAddrV4::new(server.addr(), 80);
+        match AsyncTcpStream::connect(&mut client, addr).await {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            Ok(_) => panic!("connected to a port nobody listens on"),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tasks_sharing_a_listener_all_wake_up() {
+        use std::{sync::Arc, time::Duration};
+
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = Arc::new(AsyncTcpListener::from(server.bind(80).unwrap()));
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let listener = listener.clone();
+                tokio::spawn(async move { listener.accept().await.map(drop) })
+            })
+            .collect();
+        // Both wait on the listener before anything connects.
+        tokio::task::yield_now().await;
+
+        let addr = SocketAddrV4::new(server.addr(), 80);
+        let _first = AsyncTcpStream::connect(&mut client, addr).await.unwrap();
+        let _second = AsyncTcpStream::connect(&mut client, addr).await.unwrap();
+        for task in tasks {
+            let accepted = tokio::time::timeout(Duration::from_secs(5), task);
+            accepted.await.expect("accept hung").unwrap().unwrap();
+        }
+    }
+}
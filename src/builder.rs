@@ -0,0 +1,333 @@
+//! Setting up an interface and the tun device it runs over.
+//!
+//! [`InterfaceBuilder`] creates the device, gives the kernel's end of it an
+//! address and brings it up, so the stack is reachable without any `ip`
+//! commands:
+//!
+//! ```no_run
+//! # use std::net::Ipv4Addr;
+//! # use user_space_tcp::Interface;
+//! # fn main() -> std::io::Result<()> {
+//! let mut interface = Interface::builder()
+//!     .device_name("tun1")
+//!     .addr(Ipv4Addr::new(10, 1, 0, 2), 24)
+//!     .send_buffer_size(64 << 10)
+//!     .build()?;
+//! // The kernel is at 10.1.0.1, the first free address of the network.
+//! let listener = interface.bind(80)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Creating and configuring devices takes CAP_NET_ADMIN.
+
+use std::{io, net::Ipv4Addr, time::Duration};
+
+use crate::{
+    device::{NetDevice, TunDevice},
+    polled::PolledInterface,
+    stream::{self, Interface},
+    tcp,
+};
+
+const DEFAULT_DEVICE_NAME: &str = "tun0";
+const DEFAULT_PREFIX_LEN: u8 = 24;
+const DEFAULT_MTU: usize = 1500;
+// Smallest MTU every IPv4 link must carry, RFC 791.
+const MIN_MTU: usize = 68;
+// Longer names don't fit in an interface request.
+const MAX_DEVICE_NAME_LEN: usize = libc::IFNAMSIZ - 1;
+
+/// Configures an [`Interface`] and the tun device it runs over.
+///
+/// The stack answers on one address of a network and the kernel's end of
+/// the device gets another, by default the first one that is free.
+#[derive(Clone, Debug)]
+pub struct InterfaceBuilder {
+    device_name: String,
+    addr: Ipv4Addr,
+    prefix_len: u8,
+    host_addr: Option<Ipv4Addr>,
+    mtu: usize,
+    config: tcp::Config,
+}
+
+impl Default for InterfaceBuilder {
+    fn default() -> Self {
+        InterfaceBuilder::new()
+    }
+}
+
+impl InterfaceBuilder {
+    /// The stack at 192.168.0.2/24 on `tun0`, with an MTU of 1500 bytes.
+    pub fn new() -> Self {
+        InterfaceBuilder {
+            device_name: DEFAULT_DEVICE_NAME.to_owned(),
+            addr: stream::LOCAL_ADDR,
+            prefix_len: DEFAULT_PREFIX_LEN,
+            host_addr: None,
+            mtu: DEFAULT_MTU,
+            config: Default::default(),
+        }
+    }
+
+    /// Name of the tun device, created unless it exists already. A pattern
+    /// like `tun%d` has the kernel pick a free one.
+    pub fn device_name(mut self, name: &str) -> Self {
+        self.device_name = name.to_owned();
+        self
+    }
+
+    /// Address the stack answers on, in a network of `prefix_len` bits the
+    /// kernel routes through the device.
+    pub fn addr(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        self.addr = addr;
+        self.prefix_len = prefix_len;
+        self
+    }
+
+    /// Address of the kernel's end of the device, in the same network as
+    /// the stack.
+    pub fn host_addr(mut self, addr: Ipv4Addr) -> Self {
+        self.host_addr = Some(addr);
+        self
+    }
+
+    /// Largest datagram the device carries, IP header included.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// How many bytes a connection holds on to until the peer acknowledges
+    /// them, 1 KiB by default. Writes block once it's full.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.config.send_buffer_size = size;
+        self
+    }
+
+    /// How many bytes a connection takes in before the application reads
+    /// them, at most (and by default) 65535 as there's no window scaling.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.config.recv_buffer_size = size;
+        self
+    }
+
+    /// What streams start out with, see [`stream::TcpStream::set_nonblocking`].
+    pub fn nonblocking(mut self, nonblocking: bool) -> Self {
+        self.config.options.nonblocking = nonblocking;
+        self
+    }
+
+    /// What streams start out with, see [`stream::TcpStream::set_read_timeout`].
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.options.read_timeout = timeout;
+        self
+    }
+
+    /// What streams start out with, see [`stream::TcpStream::set_write_timeout`].
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.options.write_timeout = timeout;
+        self
+    }
+
+    /// What streams start out with, see [`stream::TcpStream::set_linger`].
+    pub fn linger(mut self, linger: Option<Duration>) -> Self {
+        self.config.options.linger = linger;
+        self
+    }
+
+    /// Sets up the tun device and runs an interface over it.
+    pub fn build(&self) -> io::Result<Interface> {
+        let device = self.open_tun()?;
+        self.build_with_device(device)
+    }
+
+    /// Runs an interface over a device set up some other way, `device`
+    /// could wrap [`InterfaceBuilder::open_tun`] for instance. Only the
+    /// address and the connection settings apply, the MTU is the device's.
+    pub fn build_with_device(&self, device: impl NetDevice) -> io::Result<Interface> {
+        self.validate()?;
+        Ok(Interface::with_config(
+            device,
+            self.addr,
+            self.config.clone(),
+        ))
+    }
+
+    /// Like [`InterfaceBuilder::build`], for an interface driven by the
+    /// application's own event loop.
+    pub fn build_polled(&self) -> io::Result<PolledInterface> {
+        let device = self.open_tun()?;
+        Ok(PolledInterface::with_config(
+            device,
+            self.addr,
+            self.config.clone(),
+        ))
+    }
+
+    /// Creates the tun device, addressed and up, without running anything
+    /// over it yet.
+    pub fn open_tun(&self) -> io::Result<TunDevice> {
+        self.validate()?;
+        if self.device_name.is_empty() || self.device_name.len() > MAX_DEVICE_NAME_LEN {
+            return Err(invalid_input("Device names take 1 to 15 bytes"));
+        }
+        let mut device = TunDevice::new(&self.device_name)?;
+        device.set_mtu(self.mtu)?;
+        device.set_addr(self.kernel_addr(), self.prefix_len)?;
+        device.set_up()?;
+        Ok(device)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if !(MIN_MTU..=u16::MAX as usize).contains(&self.mtu) {
+            return Err(invalid_input("MTU must be between 68 and 65535 bytes"));
+        }
+        if self.config.send_buffer_size == 0 {
+            return Err(invalid_input("Send buffer can't be empty"));
+        }
+        if !(1..=tcp::MAX_RECV_BUFFER_SIZE).contains(&self.config.recv_buffer_size) {
+            return Err(invalid_input(
+                "Receive buffer must hold between 1 and 65535 bytes",
+            ));
+        }
+        stream::check_timeout(self.config.options.read_timeout)?;
+        stream::check_timeout(self.config.options.write_timeout)?;
+        // A /31 has room for exactly the two of us, see RFC 3021.
+        if self.prefix_len > 31 {
+            return Err(invalid_input(
+                "Network has no room for the kernel's address",
+            ));
+        }
+        if !self.is_host(self.addr) {
+            return Err(invalid_input("Address is the network's or its broadcast"));
+        }
+        let host_addr = self.kernel_addr();
+        if host_addr == self.addr {
+            return Err(invalid_input("Kernel and stack can't share an address"));
+        }
+        if host_addr.to_bits() & self.netmask() != self.addr.to_bits() & self.netmask() {
+            return Err(invalid_input("Kernel's address is outside the network"));
+        }
+        if !self.is_host(host_addr) {
+            return Err(invalid_input(
+                "Kernel's address is the network's or its broadcast",
+            ));
+        }
+        Ok(())
+    }
+
+    // Where the kernel goes, the first address of the network we don't
+    // answer on unless told otherwise.
+    fn kernel_addr(&self) -> Ipv4Addr {
+        self.host_addr.unwrap_or_else(|| {
+            let network = self.addr.to_bits() & self.netmask();
+            let first = if self.prefix_len >= 31 {
+                network
+            } else {
+                network + 1
+            };
+            if first == self.addr.to_bits() {
+                Ipv4Addr::from_bits(first + 1)
+            } else {
+                Ipv4Addr::from_bits(first)
+            }
+        })
+    }
+
+    fn netmask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+
+    // Whether `addr` isn't the network's address or its broadcast address,
+    // which /31s don't have.
+    fn is_host(&self, addr: Ipv4Addr) -> bool {
+        let host = addr.to_bits() & !self.netmask();
+        self.prefix_len >= 31 || (host != 0 && host != !self.netmask())
+    }
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemoryDevice;
+    use std::{
+        io::{Read, Write},
+        net::SocketAddrV4,
+    };
+
+    fn check_invalid(builder: InterfaceBuilder) {
+        let (device, _) = MemoryDevice::pair();
+        match builder.build_with_device(device) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("Accepted {:?}", builder),
+        }
+    }
+
+    #[test]
+    fn kernel_gets_the_first_free_address() {
+        let addr = |a, b, c, d| Ipv4Addr::new(a, b, c, d);
+        let builder = InterfaceBuilder::new();
+        assert_eq!(builder.kernel_addr(), addr(192, 168, 0, 1));
+        let builder = builder.addr(addr(10, 0, 0, 1), 8);
+        assert_eq!(builder.kernel_addr(), addr(10, 0, 0, 2));
+        let builder = builder.addr(addr(10, 0, 0, 7), 31);
+        assert_eq!(builder.kernel_addr(), addr(10, 0, 0, 6));
+        let builder = builder.host_addr(addr(10, 0, 0, 6));
+        assert_eq!(builder.kernel_addr(), addr(10, 0, 0, 6));
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let builder = InterfaceBuilder::new();
+        check_invalid(builder.clone().mtu(67));
+        check_invalid(builder.clone().mtu(65536));
+        check_invalid(builder.clone().send_buffer_size(0));
+        check_invalid(builder.clone().recv_buffer_size(0));
+        check_invalid(builder.clone().recv_buffer_size(65536));
+        check_invalid(builder.clone().read_timeout(Some(Duration::ZERO)));
+        check_invalid(builder.clone().addr(Ipv4Addr::new(10, 0, 0, 1), 32));
+        check_invalid(builder.clone().addr(Ipv4Addr::new(10, 0, 0, 0), 24));
+        check_invalid(builder.clone().addr(Ipv4Addr::new(10, 0, 0, 255), 24));
+        check_invalid(builder.clone().host_addr(stream::LOCAL_ADDR));
+        check_invalid(builder.clone().host_addr(Ipv4Addr::new(192, 168, 1, 1)));
+        check_invalid(builder.host_addr(Ipv4Addr::new(192, 168, 0, 255)));
+    }
+
+    #[test]
+    fn streams_start_with_the_configured_settings() {
+        let (a, b) = MemoryDevice::pair();
+        let builder = InterfaceBuilder::new().addr(Ipv4Addr::new(10, 0, 0, 1), 24);
+        let mut client = builder.build_with_device(a).unwrap();
+        let mut server = builder
+            .addr(Ipv4Addr::new(10, 0, 0, 2), 24)
+            .send_buffer_size(100)
+            .read_timeout(Some(Duration::from_secs(5)))
+            .build_with_device(b)
+            .unwrap();
+
+        let listener = server.bind(80).unwrap();
+        let mut stream = client
+            .connect(SocketAddrV4::new(server.addr(), 80))
+            .unwrap();
+        let mut accepted = listener.try_accept().unwrap();
+        assert_eq!(stream.read_timeout().unwrap(), None);
+        assert_eq!(
+            accepted.read_timeout().unwrap(),
+            Some(Duration::from_secs(5))
+        );
+
+        // Writes only ever take what fits in the send buffer.
+        assert_eq!(accepted.write(&[7; 1000]).unwrap(), 100);
+        let mut buf = [0; 100];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [7; 100]);
+    }
+}
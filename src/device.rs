@@ -0,0 +1,263 @@
+//! Where IP datagrams come from and go to.
+//!
+//! The stack only ever sees a [`NetDevice`]: [`TunDevice`] talks to the
+//! kernel through a tun interface, [`MemoryDevice`] hands datagrams to
+//! another device in the same process.
+
+use std::{
+    io, mem,
+    net::Ipv4Addr,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    time::Duration,
+};
+
+use bitflags::bitflags;
+use tun_tap::Mode;
+
+// What Ethernet carries, and hence what a tun device defaults to.
+const DEFAULT_MTU: usize = 1500;
+
+bitflags! {
+    /// What a device does on its own so the stack doesn't have to.
+    pub struct Capabilities: u8 {
+        /// Received datagrams can't be corrupted, checksums needn't be
+        /// verified.
+        const RX_CHECKSUM = 0b00000001;
+    }
+}
+
+/// A link carrying IPv4 datagrams, without any framing.
+pub trait NetDevice: Send + 'static {
+    /// Receives one datagram into `buf`, waiting at most `timeout` for it.
+    /// `None` if nothing arrived in time.
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>>;
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()>;
+
+    /// Largest datagram the device carries, IP header included.
+    fn mtu(&self) -> usize;
+
+    fn capabilities(&self) -> Capabilities;
+}
+
+/// A tun interface, the kernel on the other end.
+pub struct TunDevice {
+    iface: tun_tap::Iface,
+    mtu: usize,
+}
+
+impl TunDevice {
+    /// Opens (or creates) the tun interface `name`. It still has to be given
+    /// an address and brought up, see [`TunDevice::set_addr`] and
+    /// [`TunDevice::set_up`].
+    pub fn new(name: &str) -> io::Result<Self> {
+        Ok(TunDevice {
+            iface: tun_tap::Iface::without_packet_info(name, Mode::Tun)?,
+            mtu: DEFAULT_MTU,
+        })
+    }
+
+    /// Name of the interface, which the kernel picks if `new` was given a
+    /// pattern like `tun%d`.
+    pub fn name(&self) -> &str {
+        self.iface.name()
+    }
+
+    /// Changes the interface's MTU. Like the other settings this takes
+    /// CAP_NET_ADMIN.
+    pub fn set_mtu(&mut self, mtu: usize) -> io::Result<()> {
+        let mut req = self.request();
+        req.ifr_ifru.ifru_mtu = libc::c_int::try_from(mtu)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "MTU too large"))?;
+        ioctl(libc::SIOCSIFMTU, &mut req)?;
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// Gives the kernel's end of the interface `addr`, on a network of
+    /// `prefix_len` bits. The kernel routes the whole network through the
+    /// interface once it's up.
+    pub fn set_addr(&self, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        if prefix_len > 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Prefix longer than 32 bits",
+            ));
+        }
+        let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+        let mut req = self.request();
+        req.ifr_ifru.ifru_addr = sockaddr(addr);
+        ioctl(libc::SIOCSIFADDR, &mut req)?;
+        req.ifr_ifru.ifru_netmask = sockaddr(Ipv4Addr::from(mask));
+        ioctl(libc::SIOCSIFNETMASK, &mut req)
+    }
+
+    /// Brings the interface up.
+    pub fn set_up(&self) -> io::Result<()> {
+        let mut req = self.request();
+        ioctl(libc::SIOCGIFFLAGS, &mut req)?;
+        unsafe {
+            req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        }
+        ioctl(libc::SIOCSIFFLAGS, &mut req)
+    }
+
+    // An interface request naming this interface, the rest zeroed.
+    fn request(&self) -> libc::ifreq {
+        let mut req: libc::ifreq = unsafe { mem::zeroed() };
+        // Names are shorter than IFNAMSIZ, or the kernel wouldn't have
+        // created the interface, so the name stays NUL terminated.
+        for (dst, &src) in req.ifr_name.iter_mut().zip(self.name().as_bytes()) {
+            *dst = src as libc::c_char;
+        }
+        req
+    }
+}
+
+// Interfaces are configured through the ioctls of any socket, this one only
+// lives for the call.
+fn ioctl(request: libc::Ioctl, req: &mut libc::ifreq) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), request, req as *mut libc::ifreq) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // Both are 16 bytes, sockaddr_in is the IPv4 flavour of sockaddr.
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(addr) }
+}
+
+impl NetDevice for TunDevice {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let mut pfd = [libc::pollfd {
+            fd: self.iface.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        let ready = unsafe { libc::poll(pfd.as_mut_ptr(), pfd.len() as libc::nfds_t, timeout_ms) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        if ready == 0 {
+            return Ok(None);
+        }
+        self.iface.recv(buf).map(Some)
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.iface.send(datagram)?;
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+}
+
+impl AsRawFd for TunDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.iface.as_raw_fd()
+    }
+}
+
+/// One end of an in-memory link, see [`MemoryDevice::pair`].
+pub struct MemoryDevice {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    mtu: usize,
+}
+
+impl MemoryDevice {
+    /// Two devices wired back to back: what one sends the other receives.
+    pub fn pair() -> (MemoryDevice, MemoryDevice) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        let a = MemoryDevice {
+            tx: a_tx,
+            rx: a_rx,
+            mtu: DEFAULT_MTU,
+        };
+        let b = MemoryDevice {
+            tx: b_tx,
+            rx: b_rx,
+            mtu: DEFAULT_MTU,
+        };
+        (a, b)
+    }
+
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+    }
+}
+
+impl NetDevice for MemoryDevice {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let datagram = if timeout.is_zero() {
+            match self.rx.try_recv() {
+                Ok(datagram) => datagram,
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => return Ok(None),
+            }
+        } else {
+            match self.rx.recv_timeout(timeout) {
+                Ok(datagram) => datagram,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    // The other end is gone, like an unplugged cable: nothing
+                    // will ever arrive, but that's no reason to spin.
+                    std::thread::sleep(timeout);
+                    return Ok(None);
+                }
+            }
+        };
+        if datagram.len() > buf.len() {
+            // Too big for the receiver, dropped like on a real link.
+            return Ok(None);
+        }
+        buf[..datagram.len()].copy_from_slice(&datagram);
+        Ok(Some(datagram.len()))
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        if datagram.len() > self.mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Datagram larger than the MTU",
+            ));
+        }
+        // Nobody listening on the other end is the same as a lost datagram.
+        let _ = self.tx.send(datagram.to_vec());
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::RX_CHECKSUM
+    }
+}
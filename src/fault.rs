@@ -0,0 +1,493 @@
+//! A [`NetDevice`] wrapper that mistreats datagrams on purpose.
+//!
+//! [`FaultyDevice`] sits between the stack and a real device (usually
+//! [`TunDevice`](crate::device::TunDevice)) and, following a [`Policy`] for
+//! each direction, drops, delays, duplicates, reorders, corrupts or
+//! truncates what passes through. Faults are either left to chance or
+//! scripted for particular segments by sequence number.
+//!
+//! ```no_run
+//! # use user_space_tcp::{fault::{Fault, FaultyDevice, Policy, Rule}, Interface, TunDevice};
+//! # fn main() -> std::io::Result<()> {
+//! let tun = TunDevice::new("tun0")?;
+//! let mut device = FaultyDevice::new(tun, 42);
+//! device.set_rx_policy(Policy { drop: 0.05, reorder: 0.05, ..Default::default() });
+//! // Lose the first transmission of the first data segment we send.
+//! device.set_tx_policy(Policy {
+//!     script: vec![Rule { seq: 1, fault: Fault::Drop, count: 1 }],
+//!     ..Default::default()
+//! });
+//! let interface = Interface::new(device);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    io,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::{
+    device::{Capabilities, NetDevice},
+    rng::Rng,
+};
+
+const TCP_PROTOCOL: u8 = 0x06;
+// How long a datagram held back for reordering waits for another one to
+// overtake it before it goes anyway.
+const MAX_HOLD: Duration = Duration::from_millis(50);
+
+/// Something that can happen to a datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Drop,
+    Delay(Duration),
+    Duplicate,
+    /// Held back until the next datagram went past it.
+    Reorder,
+    /// A random bit is flipped.
+    Corrupt,
+    /// A random number of bytes is cut off the end.
+    Truncate,
+}
+
+/// A fault for particular segments, scripted rather than left to chance.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    /// Where the segment starts in the sequence space, relative to the SYN
+    /// of its connection: 0 is the SYN, 1 the first byte of data. Only
+    /// segments taking up sequence space match, never pure ACKs.
+    pub seq: u32,
+    pub fault: Fault,
+    /// How many matching segments are hit, 1 for only the first
+    /// transmission.
+    pub count: usize,
+}
+
+/// What happens to datagrams going one way. The probabilities are applied
+/// independently, except to segments a [`Rule`] matches.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    pub drop: f64,
+    /// Probability that a datagram is delayed by up to `max_delay`.
+    pub delay: f64,
+    pub max_delay: Duration,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub corrupt: f64,
+    pub truncate: f64,
+    pub script: Vec<Rule>,
+}
+
+impl Policy {
+    fn may_damage(&self) -> bool {
+        self.corrupt > 0.0
+            || self.truncate > 0.0
+            || self
+                .script
+                .iter()
+                .any(|rule| matches!(rule.fault, Fault::Corrupt | Fault::Truncate))
+    }
+}
+
+// A TCP connection as seen going one way: source and destination.
+type Flow = ((Ipv4Addr, u16), (Ipv4Addr, u16));
+
+/// Datagrams going one way and what the policy has in store for them.
+#[derive(Default)]
+struct Direction {
+    policy: Policy,
+    // Made it through, in order.
+    ready: VecDeque<Vec<u8>>,
+    delayed: Vec<(Instant, Vec<u8>)>,
+    // Waiting for the next datagram to overtake it.
+    held: Option<(Instant, Vec<u8>)>,
+    // Initial sequence numbers, what rules are relative to.
+    isns: HashMap<Flow, u32>,
+    // Hits left per rule.
+    hits: Vec<usize>,
+}
+
+impl Direction {
+    fn set_policy(&mut self, policy: Policy) {
+        self.hits = policy.script.iter().map(|rule| rule.count).collect();
+        self.policy = policy;
+    }
+
+    fn process(&mut self, rng: &mut Rng, now: Instant, mut datagram: Vec<u8>) {
+        let faults = match self.scripted(&datagram) {
+            Some(fault) => vec![fault],
+            None => self.chance(rng),
+        };
+        if faults.contains(&Fault::Drop) {
+            return;
+        }
+        if faults.contains(&Fault::Truncate) {
+            datagram.truncate(rng.below(datagram.len()));
+        }
+        if faults.contains(&Fault::Corrupt) && !datagram.is_empty() {
+            let bit = rng.below(datagram.len() * 8);
+            datagram[bit / 8] ^= 1 << (bit % 8);
+        }
+        if faults.contains(&Fault::Duplicate) {
+            self.pass(datagram.clone());
+        }
+        if let Some(delay) = faults.iter().find_map(|fault| match fault {
+            Fault::Delay(delay) => Some(*delay),
+            _ => None,
+        }) {
+            self.delayed.push((now + delay, datagram));
+        } else if faults.contains(&Fault::Reorder) {
+            if let Some((_, held)) = self.held.replace((now, datagram)) {
+                self.ready.push_back(held);
+            }
+        } else {
+            self.pass(datagram);
+        }
+    }
+
+    // Lets `datagram` through, along with the one waiting for it to
+    // overtake.
+    fn pass(&mut self, datagram: Vec<u8>) {
+        self.ready.push_back(datagram);
+        if let Some((_, held)) = self.held.take() {
+            self.ready.push_back(held);
+        }
+    }
+
+    /// Lets through what waited long enough.
+    fn release(&mut self, now: Instant) {
+        let mut i = 0;
+        while i < self.delayed.len() {
+            if self.delayed[i].0 <= now {
+                let (_, datagram) = self.delayed.remove(i);
+                self.pass(datagram);
+            } else {
+                i += 1;
+            }
+        }
+        if self
+            .held
+            .as_ref()
+            .is_some_and(|(at, _)| now >= *at + MAX_HOLD)
+        {
+            let (_, held) = self.held.take().unwrap();
+            self.ready.push_back(held);
+        }
+    }
+
+    /// When `release` has something to do next.
+    fn next_release(&self) -> Option<Instant> {
+        let held = self.held.as_ref().map(|(at, _)| *at + MAX_HOLD);
+        self.delayed.iter().map(|(at, _)| *at).chain(held).min()
+    }
+
+    fn chance(&mut self, rng: &mut Rng) -> Vec<Fault> {
+        let policy = &self.policy;
+        let mut faults = Vec::new();
+        if rng.chance(policy.drop) {
+            faults.push(Fault::Drop);
+        }
+        if rng.chance(policy.delay) {
+            faults.push(Fault::Delay(rng.duration_below(policy.max_delay)));
+        }
+        if rng.chance(policy.duplicate) {
+            faults.push(Fault::Duplicate);
+        }
+        if rng.chance(policy.reorder) {
+            faults.push(Fault::Reorder);
+        }
+        if rng.chance(policy.corrupt) {
+            faults.push(Fault::Corrupt);
+        }
+        if rng.chance(policy.truncate) {
+            faults.push(Fault::Truncate);
+        }
+        faults
+    }
+
+    /// The fault a rule has in store for `datagram`, if any.
+    fn scripted(&mut self, datagram: &[u8]) -> Option<Fault> {
+        if self.policy.script.is_empty() {
+            return None;
+        }
+        let ip_header = Ipv4HeaderSlice::from_slice(datagram).ok()?;
+        if ip_header.protocol().0 != TCP_PROTOCOL {
+            return None;
+        }
+        let tcp_header = TcpHeaderSlice::from_slice(&datagram[ip_header.slice().len()..]).ok()?;
+        let flow = (
+            (ip_header.source_addr(), tcp_header.source_port()),
+            (ip_header.destination_addr(), tcp_header.destination_port()),
+        );
+        let seq = tcp_header.sequence_number();
+        if tcp_header.syn() {
+            self.isns.insert(flow, seq);
+        }
+        let data_len = (ip_header.total_len() as usize)
+            .saturating_sub(ip_header.slice().len() + tcp_header.slice().len());
+        if data_len == 0 && !tcp_header.syn() && !tcp_header.fin() {
+            return None;
+        }
+        let seq = seq.wrapping_sub(*self.isns.get(&flow)?);
+        let rule = self
+            .policy
+            .script
+            .iter()
+            .zip(&mut self.hits)
+            .find(|(rule, hits)| rule.seq == seq && **hits > 0);
+        let (rule, hits) = rule?;
+        *hits -= 1;
+        Some(rule.fault)
+    }
+}
+
+/// Wraps a device, applying a [`Policy`] to what it receives and another one
+/// to what it sends.
+pub struct FaultyDevice<D> {
+    inner: D,
+    rng: Rng,
+    rx: Direction,
+    tx: Direction,
+}
+
+impl<D: NetDevice> FaultyDevice<D> {
+    /// Passes everything through unharmed until a policy is set. The same
+    /// `seed` and the same traffic give the same faults.
+    pub fn new(inner: D, seed: u64) -> Self {
+        FaultyDevice {
+            inner,
+            rng: Rng::new(seed),
+            rx: Default::default(),
+            tx: Default::default(),
+        }
+    }
+
+    /// What happens to datagrams on their way to the stack.
+    pub fn set_rx_policy(&mut self, policy: Policy) {
+        self.rx.set_policy(policy);
+    }
+
+    /// What happens to datagrams the stack sends. Corrupted ones are only
+    /// caught if the other end verifies checksums, as the kernel does.
+    pub fn set_tx_policy(&mut self, policy: Policy) {
+        self.tx.set_policy(policy);
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    // Sends what's due on the way out.
+    fn flush(&mut self, now: Instant) -> io::Result<()> {
+        self.tx.release(now);
+        while let Some(datagram) = self.tx.ready.pop_front() {
+            self.inner.send(&datagram)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: NetDevice> NetDevice for FaultyDevice<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            self.flush(now)?;
+            self.rx.release(now);
+            if let Some(datagram) = self.rx.ready.front() {
+                // Stays queued for a buffer it fits in, rather than cut short.
+                if datagram.len() > buf.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Buffer smaller than the datagram",
+                    ));
+                }
+                buf[..datagram.len()].copy_from_slice(datagram);
+                let n = datagram.len();
+                self.rx.ready.pop_front();
+                return Ok(Some(n));
+            }
+
+            // Wake up in time for whatever is delayed in either direction.
+            let until = [self.rx.next_release(), self.tx.next_release()]
+                .into_iter()
+                .flatten()
+                .fold(deadline, cmp::min);
+            match self.inner.recv(buf, until.saturating_duration_since(now))? {
+                Some(n) => self.rx.process(&mut self.rng, now, buf[..n].to_vec()),
+                None if Instant::now() >= deadline => return Ok(None),
+                None => {}
+            }
+        }
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let now = Instant::now();
+        self.tx.process(&mut self.rng, now, datagram.to_vec());
+        self.flush(now)
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = self.inner.capabilities();
+        if self.rx.policy.may_damage() {
+            capabilities.remove(Capabilities::RX_CHECKSUM);
+        }
+        capabilities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::SocketAddrV4,
+    };
+
+    use etherparse::PacketBuilder;
+
+    use super::*;
+    use crate::{device::MemoryDevice, stream::Interface};
+
+    fn segment(seq: u32, syn: bool, payload: &[u8]) -> Vec<u8> {
+        let builder =
+            PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64).tcp(4000, 80, seq, 1024);
+        let builder = if syn { builder.syn() } else { builder.ack(1) };
+        let mut datagram = Vec::new();
+        builder.write(&mut datagram, payload).unwrap();
+        datagram
+    }
+
+    #[test]
+    fn scripted_drop_hits_only_the_matching_segment() {
+        let mut rng = Rng::new(0);
+        let now = Instant::now();
+        let mut direction = Direction::default();
+        direction.set_policy(Policy {
+            script: vec![Rule {
+                seq: 1,
+                fault: Fault::Drop,
+                count: 1,
+            }],
+            ..Default::default()
+        });
+
+        let syn = segment(1000, true, &[]);
+        let ack = segment(1001, false, &[]);
+        let data = segment(1001, false, b"hello");
+        for datagram in [&syn, &ack, &data, &data] {
+            direction.process(&mut rng, now, datagram.clone());
+        }
+        assert_eq!(direction.ready, [syn, ack, data]);
+    }
+
+    #[test]
+    fn reordered_datagram_goes_after_the_next_one() {
+        let mut rng = Rng::new(0);
+        let now = Instant::now();
+        let mut direction = Direction::default();
+        let (first, second) = (segment(1, false, b"a"), segment(2, false, b"b"));
+
+        direction.set_policy(Policy {
+            reorder: 1.0,
+            ..Default::default()
+        });
+        direction.process(&mut rng, now, first.clone());
+        assert!(direction.ready.is_empty());
+        direction.set_policy(Policy::default());
+        direction.process(&mut rng, now, second.clone());
+        assert_eq!(direction.ready, [second, first]);
+    }
+
+    #[test]
+    fn held_datagram_is_released_eventually() {
+        let mut rng = Rng::new(0);
+        let now = Instant::now();
+        let mut direction = Direction::default();
+        direction.set_policy(Policy {
+            reorder: 1.0,
+            ..Default::default()
+        });
+        direction.process(&mut rng, now, segment(1, false, b"a"));
+        assert_eq!(direction.next_release(), Some(now + MAX_HOLD));
+        direction.release(now + MAX_HOLD);
+        assert_eq!(direction.ready.len(), 1);
+    }
+
+    #[test]
+    fn datagram_too_big_for_the_buffer_stays_queued() {
+        let (device, _peer) = MemoryDevice::pair();
+        let mut device = FaultyDevice::new(device, 0);
+        let datagram = segment(1, false, b"hello");
+        device.rx.ready.push_back(datagram.clone());
+
+        let mut buf = vec![0; datagram.len() - 1];
+        let err = device.recv(&mut buf, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut buf = vec![0; 1500];
+        let n = device.recv(&mut buf, Duration::ZERO).unwrap().unwrap();
+        assert_eq!(buf[..n], datagram);
+    }
+
+    #[test]
+    fn damage_turns_off_checksum_offload() {
+        let (device, _peer) = MemoryDevice::pair();
+        let mut device = FaultyDevice::new(device, 0);
+        assert!(device.capabilities().contains(Capabilities::RX_CHECKSUM));
+        device.set_rx_policy(Policy {
+            corrupt: 0.01,
+            ..Default::default()
+        });
+        assert!(!device.capabilities().contains(Capabilities::RX_CHECKSUM));
+    }
+
+    #[test]
+    fn transfer_survives_a_faulty_link() {
+        let (a, b) = MemoryDevice::pair();
+        let policy = Policy {
+            drop: 0.05,
+            delay: 0.05,
+            max_delay: Duration::from_millis(20),
+            duplicate: 0.05,
+            reorder: 0.05,
+            corrupt: 0.05,
+            truncate: 0.05,
+            script: Vec::new(),
+        };
+        // Damage is done on the way in, where checksums get verified.
+        let mut a = FaultyDevice::new(a, 1);
+        a.set_rx_policy(policy.clone());
+        let mut b = FaultyDevice::new(b, 2);
+        b.set_rx_policy(policy);
+        let mut client = Interface::with_addr(a, Ipv4Addr::new(10, 0, 0, 1));
+        let mut server = Interface::with_addr(b, Ipv4Addr::new(10, 0, 0, 2));
+
+        let listener = server.bind(80).unwrap();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let receiver = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            listener
+                .try_accept()
+                .unwrap()
+                .read_to_end(&mut received)
+                .unwrap();
+            received
+        });
+
+        let mut stream = client
+            .connect(SocketAddrV4::new(server.addr(), 80))
+            .unwrap();
+        stream.write_all(&data).unwrap();
+        drop(stream);
+        assert_eq!(receiver.join().unwrap(), data);
+    }
+}
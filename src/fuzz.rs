@@ -0,0 +1,456 @@
+//! Entry points for fuzzing the stack with untrusted input.
+//!
+//! Both take arbitrary bytes, as libFuzzer hands them out, and feed them to a
+//! stack on an in-memory device and a virtual clock:
+//!
+//! - [`datagrams`] splits the input into datagrams and hands them over as
+//!   they are, for the parsing in front of the state machine.
+//! - [`segments`] reads the input as a sequence of steps: well-formed
+//!   segments with fuzzed flags, sequence numbers, options and payload, the
+//!   clock moving on, and the application accepting, reading, writing and
+//!   closing. Sequence numbers are mostly relative to what the stack
+//!   expects, so that the fuzzer gets past the acceptability checks.
+//!   Fast Open is on and the stack's key derived from a fixed seed, so
+//!   segments can carry the cookie it issues and get data in on SYNs.
+//!
+//! After every step the stack must not have panicked, its sequence
+//! variables mustn't have moved backwards and its buffers must be within
+//! bounds. The tests below run them on random input as smoke tests, the
+//! `fuzz` directory has a cargo-fuzz target for each:
+//!
+//! ```text
+//! cargo +nightly fuzz run segments
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use etherparse::{PacketBuilder, TcpOptionElement};
+
+use crate::{
+    polled::{ListenerHandle, PolledInterface, StreamHandle},
+    seq::SeqNum,
+    sim::SimDevice,
+    stream::Quad,
+    tfo,
+};
+
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+// Ports the stack listens on, and one it doesn't.
+const PORTS: [u16; 3] = [80, 81, 82];
+const REMOTE_PORTS: [u16; 2] = [4000, 4001];
+// Largest datagram the in-memory device would ever hand over.
+const MTU: usize = 1500;
+
+/// Feeds the input to the stack as datagrams, each preceded by its length
+/// in one byte.
+pub fn datagrams(mut data: &[u8]) {
+    let mut stack = Stack::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let (datagram, rest) = rest.split_at((len as usize).min(rest.len()));
+        stack.deliver(datagram.to_vec());
+        data = rest;
+    }
+}
+
+/// Feeds the input to the stack as steps, see the module documentation.
+pub fn segments(data: &[u8]) {
+    Stack::new().run(data);
+}
+
+// The rest of the input, running out gracefully.
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(byte)
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes([self.byte().unwrap_or(0), self.byte().unwrap_or(0)])
+    }
+
+    fn u32(&mut self) -> u32 {
+        (self.u16() as u32) << 16 | self.u16() as u32
+    }
+
+    fn relative(&mut self, to: SeqNum) -> u32 {
+        match self.byte().unwrap_or(0) % 4 {
+            0 => self.u32(),
+            1 => (to + self.u16() as i16 as u32).get(),
+            _ => (to + self.byte().unwrap_or(0) as u32 % 4).get(),
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> &[u8] {
+        let (bytes, rest) = self.0.split_at(len.min(self.0.len()));
+        self.0 = rest;
+        bytes
+    }
+}
+
+struct Stack {
+    interface: PolledInterface<SimDevice>,
+    now: Instant,
+    listeners: Vec<ListenerHandle>,
+    streams: Vec<StreamHandle>,
+    // SND.UNA and RCV.NXT of every connection after the last step.
+    progress: BTreeMap<Quad, (SeqNum, Option<SeqNum>)>,
+}
+
+impl Stack {
+    fn new() -> Self {
+        let now = Instant::now();
+        let mut interface = PolledInterface::seeded(SimDevice::default(), LOCAL_ADDR, 0, now);
+        interface.set_fast_open(true);
+        let listeners = PORTS[..2]
+            .iter()
+            .map(|&port| interface.bind(port).unwrap())
+            .collect();
+        Stack {
+            interface,
+            now,
+            listeners,
+            streams: Vec::new(),
+            progress: BTreeMap::new(),
+        }
+    }
+
+    fn run(&mut self, data: &[u8]) {
+        let mut input = Input(data);
+        while let Some(step) = input.byte() {
+            match step % 8 {
+                0 => {
+                    let ms = input.byte().unwrap_or(0) as u64 * 20;
+                    self.advance(Duration::from_millis(ms));
+                }
+                1 => self.accept_and_read(),
+                2 => {
+                    let len = input.byte().unwrap_or(0) as usize * 8;
+                    self.write(input.byte().unwrap_or(0), len);
+                }
+                3 => {
+                    let how = match input.byte().unwrap_or(0) % 4 {
+                        0 => Some(Shutdown::Read),
+                        1 => Some(Shutdown::Write),
+                        2 => Some(Shutdown::Both),
+                        _ => None,
+                    };
+                    self.shutdown(input.byte().unwrap_or(0), how);
+                }
+                4 => self.connect(input.byte().unwrap_or(0)),
+                _ => {
+                    let datagram = self.segment(&mut input);
+                    self.deliver(datagram);
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, datagram: Vec<u8>) {
+        self.interface.device_mut().inbox.push_back(datagram);
+        self.poll();
+    }
+
+    fn advance(&mut self, by: Duration) {
+        let until = self.now + by;
+        while let Some(at) = self.interface.poll_at().filter(|&at| at <= until) {
+            self.now = self.now.max(at);
+            self.poll();
+        }
+        self.now = until;
+        self.poll();
+    }
+
+    fn accept_and_read(&mut self) {
+        for listener in &self.listeners {
+            while let Ok(stream) = self.interface.accept(*listener) {
+                self.streams.push(stream);
+            }
+        }
+        let mut buf = [0; 4096];
+        for stream in &self.streams {
+            while let Ok(1..) = self.interface.read(*stream, &mut buf) {}
+        }
+        self.poll();
+    }
+
+    fn write(&mut self, stream: u8, len: usize) {
+        if let Some(stream) = self.stream(stream) {
+            let _ = self.interface.write(stream, &vec![b'x'; len]);
+            self.poll();
+        }
+    }
+
+    // Closes, or resets without `how`.
+    fn shutdown(&mut self, stream: u8, how: Option<Shutdown>) {
+        let Some(stream) = self.stream(stream) else {
+            return;
+        };
+        match how {
+            Some(how) => {
+                let _ = self.interface.shutdown(stream, how);
+            }
+            None => {
+                self.streams.retain(|s| *s != stream);
+                self.interface.abort(stream);
+            }
+        }
+        self.poll();
+    }
+
+    fn connect(&mut self, port: u8) {
+        let addr = SocketAddrV4::new(REMOTE_ADDR, REMOTE_PORTS[port as usize % 2]);
+        if let Ok(stream) = self.interface.connect(addr) {
+            self.streams.push(stream);
+        }
+        self.poll();
+    }
+
+    fn stream(&self, index: u8) -> Option<StreamHandle> {
+        if self.streams.is_empty() {
+            return None;
+        }
+        Some(self.streams[index as usize % self.streams.len()])
+    }
+
+    // A segment for one of the connections (or none), made up from the
+    // input.
+    fn segment(&self, input: &mut Input) -> Vec<u8> {
+        let flags = input.byte().unwrap_or(0);
+        let endpoints = input.byte().unwrap_or(0);
+        let remote_port = REMOTE_PORTS[endpoints as usize % 2];
+        // An active open's ephemeral port, or one of ours.
+        let active = self
+            .interface
+            .coordinator()
+            .connections()
+            .map(|(quad, _)| quad)
+            .find(|quad| quad.remote().port() == remote_port && endpoints & 0b100 != 0);
+        let local_port = match active {
+            Some(quad) => quad.local().port(),
+            None => PORTS[(endpoints as usize >> 1) % PORTS.len()],
+        };
+        let quad = self
+            .interface
+            .coordinator()
+            .connections()
+            .find(|(quad, _)| {
+                quad.remote().port() == remote_port && quad.local().port() == local_port
+            })
+            .map(|(_, conn)| conn.progress());
+        let (una, rcv_nxt) = quad.unwrap_or_default();
+        let rcv_nxt = rcv_nxt.unwrap_or_default();
+
+        // Raw numbers, or offsets from what the stack expects: small ones,
+        // which hit SYN and FIN, or anything within 32 KiB.
+        let seq = input.relative(rcv_nxt);
+        let ack = input.relative(una);
+        let window = input.u16();
+
+        let mut builder = PacketBuilder::ipv4(REMOTE_ADDR.octets(), LOCAL_ADDR.octets(), 64).tcp(
+            remote_port,
+            local_port,
+            seq,
+            window,
+        );
+        if flags & 0b1 != 0 {
+            builder = builder.syn();
+        }
+        if flags & 0b10 != 0 {
+            builder = builder.fin();
+        }
+        if flags & 0b100 != 0 {
+            builder = builder.rst();
+        }
+        if flags & 0b1000 != 0 {
+            builder = builder.psh();
+        }
+        if flags & 0b10000 != 0 {
+            builder = builder.ack(ack);
+        }
+        if flags & 0b100000 != 0 {
+            builder = builder.urg(input.u16());
+        }
+        if flags & 0b1000000 != 0 {
+            // Any MSS, zero included.
+            let mss = TcpOptionElement::MaximumSegmentSize(input.u16());
+            builder = builder.options(&[mss]).expect("MSS fits");
+        } else if flags & 0b10000000 != 0 {
+            let kind = input.byte().unwrap_or(0);
+            let options = if kind & 1 == 0 {
+                // The cookie the stack issues us, padded with end of option
+                // list.
+                let cookie = self.interface.coordinator().fast_open().cookie(REMOTE_ADDR);
+                let mut options = vec![tfo::OPTION_KIND, cookie.len() as u8 + 2];
+                options.extend(cookie);
+                options.resize(options.len().next_multiple_of(4), 0);
+                options
+            } else {
+                // Fast Open cookies of any size, and whatever else fits.
+                let options_len = (kind as usize / 2 % 11) * 4;
+                input.bytes(options_len).to_vec()
+            };
+            builder = builder
+                .options_raw(&options)
+                .expect("Options are a multiple of 4 bytes, at most 40");
+        }
+        let payload_len = input.byte().unwrap_or(0) as usize * 8;
+        let payload = input.bytes(payload_len).to_vec();
+        let mut datagram = Vec::new();
+        builder
+            .write(&mut datagram, &payload)
+            .expect("Writing to a Vec");
+        datagram
+    }
+
+    // Lets the stack process what it got and checks it's still sane.
+    fn poll(&mut self) {
+        self.interface
+            .poll_once(self.now)
+            .expect("Simulated devices don't fail");
+        for datagram in self.interface.device_mut().outbox.drain(..) {
+            assert!(
+                datagram.len() <= MTU,
+                "Sent a {} byte datagram",
+                datagram.len()
+            );
+        }
+
+        let mut progress = BTreeMap::new();
+        for (quad, conn) in self.interface.coordinator().connections() {
+            conn.check_invariants();
+            let (una, rcv_nxt) = conn.progress();
+            if let Some(&(old_una, old_rcv_nxt)) = self.progress.get(quad) {
+                assert!(
+                    una >= old_una,
+                    "SND.UNA went back from {} to {}",
+                    old_una,
+                    una
+                );
+                if let (Some(old_rcv_nxt), Some(rcv_nxt)) = (old_rcv_nxt, rcv_nxt) {
+                    assert!(
+                        rcv_nxt >= old_rcv_nxt,
+                        "RCV.NXT went back from {} to {}",
+                        old_rcv_nxt,
+                        rcv_nxt
+                    );
+                }
+            }
+            progress.insert(*quad, (una, rcv_nxt));
+        }
+        self.progress = progress;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    // Override with FUZZ_ITERATIONS for a longer run.
+    fn iterations() -> usize {
+        std::env::var("FUZZ_ITERATIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(300)
+    }
+
+    fn random_input(rng: &mut Rng, max_len: usize) -> Vec<u8> {
+        let len = rng.below(max_len);
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    #[test]
+    fn random_datagrams() {
+        let mut rng = Rng::new(1);
+        let mut syn = Vec::new();
+        PacketBuilder::ipv4(REMOTE_ADDR.octets(), LOCAL_ADDR.octets(), 64)
+            .tcp(4000, 80, 0, 1024)
+            .syn()
+            .options(&[TcpOptionElement::MaximumSegmentSize(0)])
+            .unwrap()
+            .write(&mut syn, b"data")
+            .unwrap();
+        for _ in 0..iterations() {
+            // Mangled SYNs get further than random bytes.
+            let mut datagram = syn.clone();
+            for _ in 0..rng.below(4) + 1 {
+                let at = rng.below(datagram.len());
+                datagram[at] = rng.next_u64() as u8;
+            }
+            datagram.truncate(rng.below(datagram.len() + 1));
+            let mut input = vec![datagram.len() as u8];
+            input.extend(datagram);
+            input.extend(random_input(&mut rng, 256));
+            datagrams(&input);
+        }
+    }
+
+    // Steps for a handshake on port 80, accepted.
+    fn handshake() -> Vec<u8> {
+        let mut steps = Vec::new();
+        // SYN with sequence number 1000.
+        steps.extend([5, 0b1, 0, 0, 0, 0, 0x03, 0xe8, 0, 0, 0, 0, 0, 0xff, 0xff, 0]);
+        // ACK for SND.UNA + 1 at RCV.NXT.
+        steps.extend([5, 0b10000, 0, 2, 0, 2, 1, 0xff, 0xff, 0]);
+        steps.push(1);
+        steps
+    }
+
+    #[test]
+    fn handshake_then_random_segments() {
+        let mut rng = Rng::new(3);
+        for _ in 0..iterations() {
+            let mut input = handshake();
+            input.extend(random_input(&mut rng, 2048));
+            segments(&input);
+        }
+    }
+
+    // Used to retransmit empty segments forever.
+    #[test]
+    fn zero_mss_does_not_stall() {
+        let mut input = vec![
+            5, 0b1000001, 0, 0, 0, 0, 0x03, 0xe8, 0, 0, 0, 0, 0, 0xff, 0xff,
+        ];
+        // MSS 0 and no payload.
+        input.extend([0, 0, 0]);
+        input.extend(&handshake()[16..]);
+        // Write 80 bytes and wait a second.
+        input.extend([2, 10, 0, 0, 50]);
+        segments(&input);
+    }
+
+    #[test]
+    fn syn_data_with_the_stacks_cookie_is_accepted() {
+        // SYN with options and sequence number 1000.
+        let mut input = vec![
+            5, 0b10000001, 0, 0, 0, 0, 0x03, 0xe8, 0, 0, 0, 0, 0, 0xff, 0xff,
+        ];
+        // The stack's cookie and 8 bytes of data.
+        input.extend([0, 1]);
+        input.extend(b"SYN data");
+        let mut stack = Stack::new();
+        stack.run(&input);
+        let listener = stack.listeners[0];
+        let stream = stack.interface.accept(listener).unwrap();
+        let mut buf = [0; 16];
+        let n = stack.interface.read(stream, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"SYN data");
+    }
+
+    #[test]
+    fn random_segments() {
+        let mut rng = Rng::new(2);
+        for _ in 0..iterations() {
+            segments(&random_input(&mut rng, 2048));
+        }
+    }
+}
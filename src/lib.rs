@@ -0,0 +1,59 @@
+//! A TCP stack in user space, running over a tun device or anything else
+//! that carries IPv4 datagrams.
+//!
+//! [`Interface`] runs the stack on a thread of its own and hands out
+//! [`TcpListener`]s and [`TcpStream`]s that work like the ones in
+//! `std::net`. [`InterfaceBuilder`] sets up the tun device and the settings
+//! connections start out with. [`PolledInterface`] is the same stack driven
+//! from the application's own event loop instead.
+//!
+//! ```no_run
+//! # use std::{io::Write, net::Ipv4Addr};
+//! # use user_space_tcp::Interface;
+//! # fn main() -> std::io::Result<()> {
+//! let mut interface = Interface::builder()
+//!     .addr(Ipv4Addr::new(192, 168, 0, 2), 24)
+//!     .build()?;
+//! let listener = interface.bind(80)?;
+//! let mut stream = listener.try_accept()?;
+//! stream.write_all(b"hello")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Errors are [`std::io::Error`]s with the kinds `std::net` would use:
+//! `ConnectionRefused`, `ConnectionReset`, `TimedOut`, `WouldBlock`,
+//! `InvalidInput` for bad arguments and so on.
+
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub mod async_io;
+pub mod builder;
+pub mod device;
+#[cfg(any(test, feature = "testing"))]
+pub mod fault;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+pub mod pcap;
+pub mod poll;
+pub mod polled;
+#[cfg(any(test, feature = "testing"))]
+pub mod replay;
+#[cfg(any(test, feature = "testing"))]
+mod rng;
+#[cfg(test)]
+mod script;
+mod seq;
+#[cfg(any(test, feature = "testing"))]
+pub mod sim;
+mod siphash;
+pub mod stream;
+mod syncookie;
+mod tcp;
+mod tfo;
+
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub use async_io::{AsyncTcpListener, AsyncTcpStream};
+pub use builder::InterfaceBuilder;
+pub use device::{Capabilities, MemoryDevice, NetDevice, TunDevice};
+pub use polled::{ListenerHandle, PolledInterface, StreamHandle};
+pub use stream::{Interface, Stats, TcpListener, TcpStream};
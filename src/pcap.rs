@@ -0,0 +1,355 @@
+//! Recording traffic in the pcap format, for Wireshark or tcpdump.
+//!
+//! A [`Capture`] handed to [`Interface::set_capture`](crate::stream::Interface::set_capture)
+//! gets every datagram the interface receives and sends, the way tcpdump on
+//! `tun0` would see it, but also works for in-memory devices. Datagrams are
+//! written as they are, without link layer (`LINKTYPE_RAW`), in the classic
+//! pcap format rather than pcapng. Records are buffered and flushed when the
+//! capture is dropped, e.g. replaced with `set_capture(None)`.
+//!
+//! ```no_run
+//! # use user_space_tcp::{pcap::Capture, Interface};
+//! # fn main() -> std::io::Result<()> {
+//! # let mut interface = Interface::builder().build()?;
+//! let mut capture = Capture::create("trace.pcap")?;
+//! capture.set_filter(|_local, remote| remote.port() == 5900);
+//! interface.set_capture(Some(capture));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`PcapReader`] reads such files back, as well as what tcpdump records on
+//! a tun device, an Ethernet interface or `any`.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::SocketAddrV4,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+// Same, with nanosecond timestamps.
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const VERSION: (u16, u16) = (2, 4);
+const SNAPLEN: u32 = 65535;
+// Datagrams starting with the IP header.
+pub(crate) const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_ETHERNET: u32 = 1;
+// What tcpdump -i any records.
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const TCP_PROTOCOL: u8 = 0x06;
+
+/// Writes datagrams to a pcap file.
+pub struct PcapWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Starts the file with the pcap header.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&MAGIC.to_le_bytes())?;
+        inner.write_all(&VERSION.0.to_le_bytes())?;
+        inner.write_all(&VERSION.1.to_le_bytes())?;
+        // Timestamps are UTC and accurate.
+        inner.write_all(&0i32.to_le_bytes())?;
+        inner.write_all(&0u32.to_le_bytes())?;
+        inner.write_all(&SNAPLEN.to_le_bytes())?;
+        inner.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(PcapWriter { inner })
+    }
+
+    /// Appends `datagram`, seen at `timestamp` (since the Unix epoch).
+    pub fn write(&mut self, timestamp: Duration, datagram: &[u8]) -> io::Result<()> {
+        let captured = datagram.len().min(SNAPLEN as usize);
+        self.inner
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.inner
+            .write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.inner.write_all(&(captured as u32).to_le_bytes())?;
+        self.inner
+            .write_all(&(datagram.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&datagram[..captured])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Reads the datagrams out of a pcap file.
+pub struct PcapReader<R: Read> {
+    inner: R,
+    big_endian: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        PcapReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Checks the pcap header at the start of `inner`.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0; 24];
+        inner.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let (big_endian, nanos) = match magic {
+            MAGIC => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            // pcapng starts with 0x0a0d0d0a.
+            _ => return Err(invalid("Not a pcap file")),
+        };
+        let mut reader = PcapReader {
+            inner,
+            big_endian,
+            nanos,
+            linktype: 0,
+        };
+        reader.linktype = reader.u32_at(&header, 20);
+        match reader.linktype {
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_ETHERNET | LINKTYPE_LINUX_SLL => Ok(reader),
+            _ => Err(invalid("Unsupported link type")),
+        }
+    }
+
+    /// The next IPv4 datagram and when it was captured (since the Unix
+    /// epoch), `None` at the end of the file. Frames carrying anything but
+    /// IPv4 are skipped.
+    pub fn next_datagram(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+        loop {
+            let mut header = [0; 16];
+            match self.inner.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let secs = self.u32_at(&header, 0) as u64;
+            let fraction = self.u32_at(&header, 4);
+            let timestamp = if self.nanos {
+                Duration::new(secs, fraction)
+            } else {
+                Duration::new(secs, 0) + Duration::from_micros(fraction as u64)
+            };
+            let mut frame = vec![0; self.u32_at(&header, 8) as usize];
+            self.inner.read_exact(&mut frame)?;
+
+            let (ethertype, header_len) = match self.linktype {
+                LINKTYPE_ETHERNET => (frame.get(12..14), 14),
+                LINKTYPE_LINUX_SLL => (frame.get(14..16), 16),
+                _ => return Ok(Some((timestamp, frame))),
+            };
+            if ethertype == Some(&ETHERTYPE_IPV4.to_be_bytes()[..]) {
+                frame.drain(..header_len);
+                return Ok(Some((timestamp, frame)));
+            }
+        }
+    }
+
+    fn u32_at(&self, bytes: &[u8], at: usize) -> u32 {
+        let bytes = bytes[at..at + 4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<(Duration, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_datagram().transpose()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+type Filter = Box<dyn FnMut(SocketAddrV4, SocketAddrV4) -> bool + Send>;
+
+/// Where an interface records its traffic, see the module documentation.
+pub struct Capture {
+    writer: PcapWriter<Box<dyn Write + Send>>,
+    filter: Option<Filter>,
+    // Wall clock time at `started`, to turn the stack's instants into
+    // timestamps.
+    wall_clock: SystemTime,
+    started: Instant,
+}
+
+impl Capture {
+    /// Records into a new file at `path`, replacing it if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Capture::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Records into `writer`.
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        Ok(Capture {
+            writer: PcapWriter::new(writer)?,
+            filter: None,
+            wall_clock: SystemTime::now(),
+            started: Instant::now(),
+        })
+    }
+
+    /// Only records TCP segments for which `filter`, given our end and the
+    /// peer's end of the connection, returns true. Datagrams that aren't TCP
+    /// are left out as well.
+    pub fn set_filter(
+        &mut self,
+        filter: impl FnMut(SocketAddrV4, SocketAddrV4) -> bool + Send + 'static,
+    ) {
+        self.filter = Some(Box::new(filter));
+    }
+
+    /// Records `datagram`, received if `incoming` and sent otherwise, at
+    /// `now`.
+    pub(crate) fn record(
+        &mut self,
+        datagram: &[u8],
+        incoming: bool,
+        now: Instant,
+    ) -> io::Result<()> {
+        if let Some(filter) = &mut self.filter {
+            let Some((src, dst)) = endpoints(datagram) else {
+                return Ok(());
+            };
+            let (local, remote) = if incoming { (dst, src) } else { (src, dst) };
+            if !filter(local, remote) {
+                return Ok(());
+            }
+        }
+        let timestamp = self.wall_clock + now.saturating_duration_since(self.started);
+        let timestamp = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.writer.write(timestamp, datagram)
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        // Nobody left to report an error to.
+        let _ = self.writer.flush();
+    }
+}
+
+// Source and destination of a TCP segment.
+fn endpoints(datagram: &[u8]) -> Option<(SocketAddrV4, SocketAddrV4)> {
+    let ip_header = Ipv4HeaderSlice::from_slice(datagram).ok()?;
+    if ip_header.protocol().0 != TCP_PROTOCOL {
+        return None;
+    }
+    let tcp_header = TcpHeaderSlice::from_slice(&datagram[ip_header.slice().len()..]).ok()?;
+    Some((
+        SocketAddrV4::new(ip_header.source_addr(), tcp_header.source_port()),
+        SocketAddrV4::new(ip_header.destination_addr(), tcp_header.destination_port()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_header_and_records() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write(Duration::new(7, 1_000), &[0x45, 0, 0, 20])
+            .unwrap();
+        let file = writer.into_inner();
+
+        assert_eq!(file.len(), 24 + 16 + 4);
+        assert_eq!(file[..4], MAGIC.to_le_bytes());
+        assert_eq!(file[20..24], LINKTYPE_RAW.to_le_bytes());
+        let record = &file[24..];
+        assert_eq!(record[..4], 7u32.to_le_bytes());
+        assert_eq!(record[4..8], 1u32.to_le_bytes());
+        assert_eq!(record[8..12], 4u32.to_le_bytes());
+        assert_eq!(record[12..16], 4u32.to_le_bytes());
+        assert_eq!(record[16..], [0x45, 0, 0, 20]);
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write(Duration::new(7, 1_000), b"first").unwrap();
+        writer.write(Duration::new(8, 0), b"second").unwrap();
+        let file = writer.into_inner();
+
+        let datagrams: Vec<_> = PcapReader::new(&file[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            datagrams,
+            [
+                (Duration::new(7, 1_000), b"first".to_vec()),
+                (Duration::new(8, 0), b"second".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_ethernet_frames() {
+        let mut file = Vec::new();
+        for word in [MAGIC_NANOS, 0x0002_0004, 0, 0, SNAPLEN, LINKTYPE_ETHERNET] {
+            file.extend(word.to_be_bytes());
+        }
+        for (ethertype, payload) in [(0x86dd_u16, &b"ipv6"[..]), (ETHERTYPE_IPV4, b"ipv4")] {
+            for word in [1, 5, 18, 18] {
+                file.extend(u32::to_be_bytes(word));
+            }
+            file.extend([0; 12]);
+            file.extend(ethertype.to_be_bytes());
+            file.extend(payload);
+        }
+
+        let mut reader = PcapReader::new(&file[..]).unwrap();
+        assert_eq!(
+            reader.next_datagram().unwrap(),
+            Some((Duration::new(1, 5), b"ipv4".to_vec()))
+        );
+        assert_eq!(reader.next_datagram().unwrap(), None);
+    }
+
+    #[test]
+    fn capture_is_flushed_once_dropped() {
+        // Shows what made it past the capture's buffer.
+        #[derive(Clone, Default)]
+        struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let file = Shared::default();
+        let mut capture = Capture::new(BufWriter::new(file.clone())).unwrap();
+        capture.record(b"datagram", true, Instant::now()).unwrap();
+        assert!(file.0.lock().unwrap().is_empty());
+        drop(capture);
+        assert_eq!(file.0.lock().unwrap().len(), 24 + 16 + 8);
+    }
+}
@@ -0,0 +1,118 @@
+//! Readiness notifications for many sockets at once, in the spirit of mio.
+//!
+//! Streams and listeners are registered with [`Interface::register`] under a
+//! [`Token`] of the caller's choosing, [`Interface::poll`] then waits until at
+//! least one of them is ready and reports which. Readiness is level triggered:
+//! a stream with unread data keeps showing up until it has been read.
+//!
+//! [`Interface::register`]: crate::stream::Interface::register
+//! [`Interface::poll`]: crate::stream::Interface::poll
+
+use bitflags::bitflags;
+
+use crate::stream::{Quad, TcpListener, TcpStream};
+
+/// Caller chosen identifier handed back in the [`Event`]s of a source.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Token(pub usize);
+
+bitflags! {
+    /// What a source is ready for. `HUP` and `ERROR` are reported whether or
+    /// not they were asked for.
+    pub struct Ready: u8 {
+        /// A read or accept won't block.
+        const READABLE = 0b0001;
+        /// A write won't block.
+        const WRITABLE = 0b0010;
+        /// The peer is done sending, reads return 0 once the data is drained.
+        const HUP = 0b0100;
+        /// The connection was reset or timed out.
+        const ERROR = 0b1000;
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Event {
+    token: Token,
+    ready: Ready,
+}
+
+impl Event {
+    pub(crate) fn new(token: Token, ready: Ready) -> Self {
+        Event { token, ready }
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    pub fn readiness(&self) -> Ready {
+        self.ready
+    }
+}
+
+/// Buffer [`Interface::poll`](crate::stream::Interface::poll) fills in.
+#[derive(Default)]
+pub struct Events(Vec<Event>);
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Event> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub(crate) fn push(&mut self, event: Event) {
+        self.0.push(event);
+    }
+}
+
+impl<'a> IntoIterator for &'a Events {
+    type Item = &'a Event;
+    type IntoIter = std::slice::Iter<'a, Event>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Opaque handle for a stream or listener registered with an `Interface`.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Socket(pub(crate) SocketKind);
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub(crate) enum SocketKind {
+    Stream(Quad),
+    Listener(u16),
+}
+
+/// Something [`Interface::poll`](crate::stream::Interface::poll) can watch.
+pub trait Source {
+    fn socket(&self) -> Socket;
+}
+
+impl Source for TcpStream {
+    fn socket(&self) -> Socket {
+        Socket(SocketKind::Stream(self.quad()))
+    }
+}
+
+impl Source for TcpListener {
+    fn socket(&self) -> Socket {
+        Socket(SocketKind::Listener(self.port()))
+    }
+}
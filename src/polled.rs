@@ -0,0 +1,308 @@
+//! An interface the application drives from its own event loop.
+//!
+//! [`Interface`](crate::stream::Interface) runs `packet_loop` on a thread of
+//! its own and shares the connections with the streams through a mutex.
+//! [`PolledInterface`] owns everything instead: the application waits for the
+//! device to become readable (or for the deadline from
+//! [`PolledInterface::poll_at`] to pass), calls [`PolledInterface::poll_once`]
+//! and then works with its sockets through plain handles.
+//!
+//! ```no_run
+//! # use std::time::Instant;
+//! # use user_space_tcp::PolledInterface;
+//! # fn main() -> std::io::Result<()> {
+//! let mut iface = PolledInterface::default();
+//! let listener = iface.bind(5900)?;
+//! loop {
+//!     // wait until iface.as_raw_fd() is readable or iface.poll_at() passed
+//!     iface.poll_once(Instant::now())?;
+//!     while let Ok(stream) = iface.accept(listener) { /* ... */ }
+//! }
+//! # }
+//! ```
+
+use std::{
+    io,
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    os::unix::io::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    builder::InterfaceBuilder,
+    device::{NetDevice, TunDevice},
+    pcap::Capture,
+    poll::{Ready, Socket, SocketKind, Source},
+    stream::{self, ConnectionCoordinator, Quad, Stats},
+    tcp,
+};
+
+/// A connection of a [`PolledInterface`].
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct StreamHandle(Quad);
+
+/// A listening port of a [`PolledInterface`].
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct ListenerHandle(u16);
+
+pub struct PolledInterface<D: NetDevice = TunDevice> {
+    device: D,
+    buf: Vec<u8>,
+    coordinator: ConnectionCoordinator,
+    // Time of the last poll_once. poll_at returns it when there's work to do
+    // right away, as in "already due".
+    last_poll: Instant,
+}
+
+impl Default for PolledInterface {
+    /// An interface on `tun0` with the [`InterfaceBuilder`] defaults.
+    fn default() -> Self {
+        InterfaceBuilder::new()
+            .build_polled()
+            .expect("Failed to set up the tun interface")
+    }
+}
+
+impl<D: NetDevice + AsRawFd> AsRawFd for PolledInterface<D> {
+    /// The device, readable when [`PolledInterface::poll_once`] has
+    /// segments to process.
+    fn as_raw_fd(&self) -> RawFd {
+        self.device.as_raw_fd()
+    }
+}
+
+impl<D: NetDevice> PolledInterface<D> {
+    pub fn new(device: D) -> Self {
+        PolledInterface::with_addr(device, stream::LOCAL_ADDR)
+    }
+
+    /// Like [`PolledInterface::new`], answering on `addr`.
+    pub fn with_addr(device: D, addr: Ipv4Addr) -> Self {
+        PolledInterface::with_config(device, addr, Default::default())
+    }
+
+    pub(crate) fn with_config(device: D, addr: Ipv4Addr, config: tcp::Config) -> Self {
+        PolledInterface {
+            buf: vec![0; device.mtu()],
+            coordinator: ConnectionCoordinator::for_device(&device, addr, config),
+            device,
+            last_poll: Instant::now(),
+        }
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    /// Like [`PolledInterface::with_addr`], but reproducible: the secrets
+    /// are derived from `seed` and the clock starts at `epoch`.
+    pub(crate) fn seeded(device: D, addr: Ipv4Addr, seed: u64, epoch: Instant) -> Self {
+        PolledInterface::seeded_with_config(device, addr, Default::default(), seed, epoch)
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn seeded_with_config(
+        device: D,
+        addr: Ipv4Addr,
+        config: tcp::Config,
+        seed: u64,
+        epoch: Instant,
+    ) -> Self {
+        let mut interface = PolledInterface::with_config(device, addr, config);
+        interface.coordinator.seed(seed, epoch);
+        interface.last_poll = epoch;
+        interface
+    }
+
+    /// The device the interface runs over.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub(crate) fn coordinator(&self) -> &ConnectionCoordinator {
+        &self.coordinator
+    }
+
+    /// Processes every segment waiting on the device and runs the timers
+    /// that are due at `now`. Never blocks. Returns whether anything
+    /// happened that might have changed the readiness of a socket.
+    pub fn poll_once(&mut self, now: Instant) -> io::Result<bool> {
+        self.last_poll = now;
+        let mut changed = false;
+        while let Some(n) = self.device.recv(&mut self.buf, Duration::ZERO)? {
+            self.coordinator.on_datagram(&self.buf[..n], now);
+            changed = true;
+        }
+        let notifications = self.coordinator.on_tick(now);
+        stream::transmit(&mut self.device, self.coordinator.take_outbox())?;
+        Ok(changed || !notifications.is_empty())
+    }
+
+    /// Like [`Interface::set_capture`](crate::stream::Interface::set_capture).
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.coordinator.set_capture(capture);
+    }
+
+    /// Like [`Interface::stats`](crate::stream::Interface::stats).
+    pub fn stats(&self) -> Stats {
+        self.coordinator.stats()
+    }
+
+    /// Like [`Interface::set_syn_backlog`](crate::stream::Interface::set_syn_backlog).
+    pub fn set_syn_backlog(&mut self, backlog: usize) {
+        self.coordinator.set_syn_backlog(backlog);
+    }
+
+    /// Like [`Interface::set_fast_open`](crate::stream::Interface::set_fast_open).
+    pub fn set_fast_open(&mut self, enabled: bool) {
+        self.coordinator.fast_open_mut().enabled = enabled;
+    }
+
+    /// Like [`Interface::set_fast_open_key`](crate::stream::Interface::set_fast_open_key).
+    pub fn set_fast_open_key(&mut self, key: [u8; 16]) {
+        self.coordinator.fast_open_mut().rotate_key(key);
+    }
+
+    /// When [`PolledInterface::poll_once`] should be called next even if
+    /// the device doesn't become readable. `None` if nothing is pending, a
+    /// time in the past if there's work right away.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.coordinator.poll_at(self.last_poll)
+    }
+
+    pub fn bind(&mut self, port: u16) -> io::Result<ListenerHandle> {
+        self.bind_with_backlog(port, stream::DEFAULT_BACKLOG)
+    }
+
+    pub fn bind_with_backlog(&mut self, port: u16, backlog: usize) -> io::Result<ListenerHandle> {
+        self.coordinator.listen(port, backlog)?;
+        Ok(ListenerHandle(port))
+    }
+
+    /// Frees the port, resetting the connections nobody accepted yet.
+    pub fn unbind(&mut self, listener: ListenerHandle) {
+        self.coordinator.unlisten(listener.0);
+    }
+
+    /// Next established connection, `WouldBlock` if there's none yet and
+    /// `NotConnected` once the listener is unbound.
+    pub fn accept(&mut self, listener: ListenerHandle) -> io::Result<StreamHandle> {
+        match self.coordinator.accept(listener.0)? {
+            Some(quad) => Ok(StreamHandle(quad)),
+            None => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "No connection to accept",
+            )),
+        }
+    }
+
+    /// Starts a connection to `addr`, see [`PolledInterface::is_connected`].
+    pub fn connect(&mut self, addr: SocketAddrV4) -> io::Result<StreamHandle> {
+        Ok(StreamHandle(self.coordinator.open(addr, None, &[])?))
+    }
+
+    /// Whether the handshake of `stream` completed, failing if the peer
+    /// refused it or never answered.
+    pub fn is_connected(&mut self, stream: StreamHandle) -> io::Result<bool> {
+        let conn = self.coordinator.stream(&stream.0)?;
+        stream::connect_now(conn).transpose().map(|c| c.is_some())
+    }
+
+    /// The other end of `stream`.
+    pub fn peer_addr(&self, stream: StreamHandle) -> SocketAddrV4 {
+        stream.0.remote()
+    }
+
+    pub fn read(&mut self, stream: StreamHandle, buf: &mut [u8]) -> io::Result<usize> {
+        let conn = self.coordinator.stream(&stream.0)?;
+        stream::read_now(conn, buf).unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "No data available",
+            ))
+        })
+    }
+
+    pub fn write(&mut self, stream: StreamHandle, buf: &[u8]) -> io::Result<usize> {
+        let conn = self.coordinator.stream(&stream.0)?;
+        stream::write_now(conn, buf).unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Too many bytes buffered",
+            ))
+        })
+    }
+
+    /// Succeeds once the peer acknowledged everything written so far.
+    pub fn flush(&mut self, stream: StreamHandle) -> io::Result<()> {
+        let conn = self.coordinator.stream(&stream.0)?;
+        stream::flush_now(conn).unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Data not acknowledged yet",
+            ))
+        })
+    }
+
+    /// Like [`TcpStream::shutdown`](crate::stream::TcpStream::shutdown).
+    pub fn shutdown(&mut self, stream: StreamHandle, how: Shutdown) -> io::Result<()> {
+        let conn = self.coordinator.stream(&stream.0)?;
+        if let Shutdown::Read | Shutdown::Both = how {
+            conn.close_read();
+        }
+        if let Shutdown::Write | Shutdown::Both = how {
+            conn.close();
+        }
+        Ok(())
+    }
+
+    /// Gives up the handle, the connection is closed in the background.
+    pub fn close(&mut self, stream: StreamHandle) {
+        self.coordinator.release(stream.0);
+    }
+
+    /// Gives up the handle, resetting the connection.
+    pub fn abort(&mut self, stream: StreamHandle) {
+        if let Ok(conn) = self.coordinator.stream(&stream.0) {
+            conn.abort();
+        }
+        self.coordinator.release(stream.0);
+    }
+
+    /// What `source` is ready for, see [`Ready`].
+    pub fn readiness(&self, source: &impl Source) -> Ready {
+        self.coordinator.readiness(source.socket())
+    }
+}
+
+impl Source for StreamHandle {
+    fn socket(&self) -> Socket {
+        Socket(SocketKind::Stream(self.0))
+    }
+}
+
+impl Source for ListenerHandle {
+    fn socket(&self) -> Socket {
+        Socket(SocketKind::Listener(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimDevice;
+
+    #[test]
+    fn unbound_listener_fails() {
+        let mut interface = PolledInterface::new(SimDevice::default());
+        let listener = interface.bind(80).unwrap();
+        interface.unbind(listener);
+        match interface.accept(listener) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotConnected),
+            Ok(stream) => panic!("Accepted {:?}", stream),
+        }
+    }
+}
@@ -0,0 +1,492 @@
+//! Replaying a capture against the stack.
+//!
+//! [`replay`] takes a pcap file of a TCP server (ours or any other) talking
+//! to its clients, plays the clients' side to a fresh stack on a virtual
+//! clock and records what the stack answers. A capture attached to a bug
+//! report becomes a regression test:
+//!
+//! ```no_run
+//! # use user_space_tcp::{pcap::PcapReader, replay::replay};
+//! # fn main() -> std::io::Result<()> {
+//! let report = replay(PcapReader::open("captures/rst-in-syn-received.pcap")?)?;
+//! report.assert_matches();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The server's application is played as well: the ports it accepted
+//! connections on are bound, connections are accepted and read as soon as
+//! they can be, and the data and FINs the server sent are written and shut
+//! down at the time it sent them.
+//!
+//! Our initial sequence numbers aren't the recorded ones, so sequence and
+//! acknowledgment numbers are compared relative to the initial ones, the way
+//! tcpdump shows them, and the acknowledgment numbers of the clients'
+//! segments are rewritten to fit ours. Windows, options, PSH and timing
+//! aren't compared. Connections the server opened itself aren't replayed.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, io,
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+
+use crate::{
+    pcap::PcapReader,
+    polled::{ListenerHandle, PolledInterface, StreamHandle},
+    sim::SimDevice,
+};
+
+const TCP_PROTOCOL: u8 = 0x06;
+// Timer runs between two datagrams before the stack is considered stuck.
+const MAX_POLLS: usize = 1000;
+
+/// A segment, as far as comparing runs goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summary {
+    /// As tcpdump shows them: S, F and R, then `.` for ACK.
+    pub flags: String,
+    /// Relative to the sender's initial sequence number.
+    pub seq: u32,
+    /// Relative to the receiver's initial sequence number, 0 without ACK.
+    pub ack: u32,
+    pub len: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] seq {} ack {} length {}",
+            self.flags, self.seq, self.ack, self.len
+        )
+    }
+}
+
+/// What the stack sent during a replay, next to what the captured server
+/// sent. Times are relative to the first datagram of the capture.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub sent: Vec<(Duration, Summary)>,
+    pub expected: Vec<(Duration, Summary)>,
+}
+
+impl Report {
+    /// Panics unless the stack sent the same segments as the captured
+    /// server, in the same order.
+    pub fn assert_matches(&self) {
+        let sent = self.sent.iter().map(|(_, summary)| summary);
+        let expected = self.expected.iter().map(|(_, summary)| summary);
+        if sent.ne(expected) {
+            panic!("replay diverged from the capture\n{}", self);
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (title, segments) in [("sent", &self.sent), ("expected", &self.expected)] {
+            writeln!(f, "{}:", title)?;
+            for (at, summary) in segments {
+                writeln!(f, "  {:>10.6} {}", at.as_secs_f64(), summary)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Plays the clients of the capture in `reader` to a fresh stack, see the
+/// module documentation.
+pub fn replay<R: io::Read>(reader: PcapReader<R>) -> io::Result<Report> {
+    let mut packets = Vec::new();
+    for datagram in reader {
+        let (timestamp, datagram) = datagram?;
+        if let Some(packet) = Packet::parse(timestamp, datagram) {
+            packets.push(packet);
+        }
+    }
+    // The server is whoever answered a SYN.
+    let addr = packets
+        .iter()
+        .find(|packet| packet.tcp.syn && packet.tcp.ack)
+        .map(|packet| *packet.src.ip())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No connection accepted in the capture",
+            )
+        })?;
+    let ports: BTreeSet<_> = packets
+        .iter()
+        .filter(|packet| packet.tcp.syn && packet.tcp.ack && *packet.src.ip() == addr)
+        .map(|packet| packet.src.port())
+        .collect();
+
+    let start = packets[0].timestamp;
+    let mut replay = Replay::new(addr);
+    for port in ports {
+        let listener = replay.interface.bind(port)?;
+        replay.listeners.push((port, listener));
+    }
+    for packet in packets {
+        let now = replay.epoch + packet.timestamp.saturating_sub(start);
+        replay.advance(now)?;
+        if *packet.dst.ip() == addr {
+            replay.deliver(packet);
+        } else if *packet.src.ip() == addr {
+            replay.expect(packet, now);
+        } else {
+            continue;
+        }
+        replay.poll(now)?;
+    }
+    Ok(replay.report)
+}
+
+// A client talking to a port of the server.
+type FlowId = (u16, SocketAddrV4);
+
+#[derive(Default)]
+struct Flow {
+    // Initial sequence numbers of the client, the captured server and the
+    // stack.
+    client_isn: Option<u32>,
+    server_isn: Option<u32>,
+    our_isn: Option<u32>,
+    stream: Option<StreamHandle>,
+    // Relative sequence number the server's data was written up to.
+    written: u32,
+    // Written by the server but not by the application yet.
+    pending: Vec<u8>,
+    fin: bool,
+    shut_down: bool,
+}
+
+struct Replay {
+    interface: PolledInterface<SimDevice>,
+    epoch: Instant,
+    last_poll: Instant,
+    listeners: Vec<(u16, ListenerHandle)>,
+    flows: BTreeMap<FlowId, Flow>,
+    report: Report,
+}
+
+impl Replay {
+    fn new(addr: Ipv4Addr) -> Self {
+        let epoch = Instant::now();
+        Replay {
+            interface: PolledInterface::seeded(SimDevice::default(), addr, 0, epoch),
+            epoch,
+            last_poll: epoch,
+            listeners: Vec::new(),
+            flows: BTreeMap::new(),
+            report: Default::default(),
+        }
+    }
+
+    /// Runs the timers due until `now`.
+    fn advance(&mut self, now: Instant) -> io::Result<()> {
+        let mut polls = 0;
+        while let Some(at) = self.interface.poll_at().filter(|at| *at <= now) {
+            polls += 1;
+            if polls > MAX_POLLS {
+                return Err(io::Error::other("Stack keeps asking to be polled"));
+            }
+            self.poll(at.max(self.last_poll))?;
+        }
+        Ok(())
+    }
+
+    // A segment from a client, fitted to our sequence numbers.
+    fn deliver(&mut self, packet: Packet) {
+        let flow = self
+            .flows
+            .entry((packet.dst.port(), packet.src))
+            .or_default();
+        if packet.tcp.syn {
+            flow.client_isn = Some(packet.tcp.sequence_number);
+        }
+        let mut datagram = packet.datagram;
+        if let (true, Some(server_isn), Some(our_isn)) =
+            (packet.tcp.ack, flow.server_isn, flow.our_isn)
+        {
+            let mut tcp = packet.tcp;
+            tcp.acknowledgment_number = tcp
+                .acknowledgment_number
+                .wrapping_sub(server_isn)
+                .wrapping_add(our_isn);
+            datagram = rebuild(&packet.ip, tcp, &datagram[packet.payload..]);
+        }
+        self.interface.device_mut().inbox.push_back(datagram);
+    }
+
+    // A segment the server sent, what it tells about its application.
+    fn expect(&mut self, packet: Packet, now: Instant) {
+        let flow = self
+            .flows
+            .entry((packet.src.port(), packet.dst))
+            .or_default();
+        if packet.tcp.syn {
+            flow.server_isn = Some(packet.tcp.sequence_number);
+        }
+        let summary = summarize(&packet, flow.server_isn, flow.client_isn);
+        let len = (packet.datagram.len() - packet.payload) as u32;
+        let end = summary.seq.wrapping_add(len);
+        if len > 0 && flow.written < end {
+            // Retransmissions needn't be written again.
+            let skip = flow.written.saturating_sub(summary.seq) as usize;
+            flow.pending
+                .extend(&packet.datagram[packet.payload + skip..]);
+            flow.written = end;
+        }
+        flow.fin |= packet.tcp.fin;
+        self.report
+            .expected
+            .push((now.duration_since(self.epoch), summary));
+    }
+
+    // Runs the stack and its application at `now`, recording what was sent.
+    fn poll(&mut self, now: Instant) -> io::Result<()> {
+        self.last_poll = now;
+        self.interface.poll_once(now)?;
+        self.application()?;
+        // What the application did goes out right away.
+        self.interface.poll_once(now)?;
+
+        let sent: Vec<_> = self.interface.device_mut().outbox.drain(..).collect();
+        for datagram in sent {
+            let Some(packet) = Packet::parse(Duration::ZERO, datagram) else {
+                continue;
+            };
+            let flow = self
+                .flows
+                .entry((packet.src.port(), packet.dst))
+                .or_default();
+            if packet.tcp.syn {
+                flow.our_isn = Some(packet.tcp.sequence_number);
+            }
+            let summary = summarize(&packet, flow.our_isn, flow.client_isn);
+            self.report
+                .sent
+                .push((now.duration_since(self.epoch), summary));
+        }
+        Ok(())
+    }
+
+    // Accepts and reads what it can, writes what the server wrote.
+    fn application(&mut self) -> io::Result<()> {
+        for (port, listener) in &self.listeners {
+            while let Ok(stream) = self.interface.accept(*listener) {
+                let id = (*port, self.interface.peer_addr(stream));
+                self.flows.entry(id).or_default().stream = Some(stream);
+            }
+        }
+        let mut buf = [0; 4096];
+        for flow in self.flows.values_mut() {
+            let Some(stream) = flow.stream else {
+                continue;
+            };
+            while let Ok(1..) = self.interface.read(stream, &mut buf) {}
+            while !flow.pending.is_empty() {
+                match self.interface.write(stream, &flow.pending) {
+                    Ok(n) => drop(flow.pending.drain(..n)),
+                    Err(_) => break,
+                }
+            }
+            if flow.fin && flow.pending.is_empty() && !flow.shut_down {
+                self.interface.shutdown(stream, Shutdown::Write)?;
+                flow.shut_down = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+// A TCP segment out of the capture.
+struct Packet {
+    timestamp: Duration,
+    ip: Ipv4Header,
+    tcp: TcpHeader,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    datagram: Vec<u8>,
+    // Where the payload starts in `datagram`.
+    payload: usize,
+}
+
+impl Packet {
+    fn parse(timestamp: Duration, mut datagram: Vec<u8>) -> Option<Self> {
+        let ip_header = Ipv4HeaderSlice::from_slice(&datagram).ok()?;
+        if ip_header.protocol().0 != TCP_PROTOCOL {
+            return None;
+        }
+        let ip_header_len = ip_header.slice().len();
+        // Ethernet pads short frames.
+        let total_len = (ip_header.total_len() as usize).min(datagram.len());
+        let ip = ip_header.to_header();
+        let tcp_header = TcpHeaderSlice::from_slice(&datagram[ip_header_len..total_len]).ok()?;
+        let payload = ip_header_len + tcp_header.slice().len();
+        let tcp = tcp_header.to_header();
+        datagram.truncate(total_len);
+        Some(Packet {
+            timestamp,
+            src: SocketAddrV4::new(ip.source.into(), tcp.source_port),
+            dst: SocketAddrV4::new(ip.destination.into(), tcp.destination_port),
+            ip,
+            tcp,
+            datagram,
+            payload,
+        })
+    }
+}
+
+fn summarize(packet: &Packet, sender_isn: Option<u32>, receiver_isn: Option<u32>) -> Summary {
+    let tcp = &packet.tcp;
+    let mut flags = String::new();
+    for (set, flag) in [
+        (tcp.syn, 'S'),
+        (tcp.fin, 'F'),
+        (tcp.rst, 'R'),
+        (tcp.ack, '.'),
+    ] {
+        if set {
+            flags.push(flag);
+        }
+    }
+    let ack = if tcp.ack {
+        tcp.acknowledgment_number
+            .wrapping_sub(receiver_isn.unwrap_or(0))
+    } else {
+        0
+    };
+    Summary {
+        flags,
+        seq: tcp.sequence_number.wrapping_sub(sender_isn.unwrap_or(0)),
+        ack,
+        len: packet.datagram.len() - packet.payload,
+    }
+}
+
+// The datagram with `tcp` as its header, checksums fixed up.
+fn rebuild(ip: &Ipv4Header, mut tcp: TcpHeader, payload: &[u8]) -> Vec<u8> {
+    tcp.checksum = tcp
+        .calc_checksum_ipv4(ip, payload)
+        .expect("Payload fit before");
+    let mut datagram = Vec::new();
+    ip.write(&mut datagram).expect("Writing to a Vec");
+    tcp.write(&mut datagram).expect("Writing to a Vec");
+    datagram.extend(payload);
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+
+    use super::*;
+    use crate::{
+        pcap::{Capture, PcapWriter},
+        sim::Simulator,
+    };
+
+    const CLIENT: [u8; 4] = [10, 0, 0, 1];
+    const SERVER: [u8; 4] = [10, 0, 0, 2];
+
+    fn segment(from_client: bool, flags: &str, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+        let builder = if from_client {
+            PacketBuilder::ipv4(CLIENT, SERVER, 64).tcp(4000, 80, seq, 1024)
+        } else {
+            PacketBuilder::ipv4(SERVER, CLIENT, 64).tcp(80, 4000, seq, 1024)
+        };
+        let builder = if flags.contains('S') {
+            builder.syn()
+        } else {
+            builder
+        };
+        let builder = if flags.contains('F') {
+            builder.fin()
+        } else {
+            builder
+        };
+        let builder = if flags.contains('.') {
+            builder.ack(ack)
+        } else {
+            builder
+        };
+        let mut datagram = Vec::new();
+        builder.write(&mut datagram, payload).unwrap();
+        datagram
+    }
+
+    #[test]
+    fn replays_a_handwritten_capture() {
+        // The server starts at 5000, the stack won't.
+        let capture = [
+            segment(true, "S", 100, 0, b""),
+            segment(false, "S.", 5000, 101, b""),
+            segment(true, ".", 101, 5001, b""),
+            segment(true, ".", 101, 5001, b"hello"),
+            segment(false, ".", 5001, 106, b""),
+            segment(false, ".", 5001, 106, b"world"),
+            segment(true, ".", 106, 5006, b""),
+            segment(true, "F.", 106, 5006, b""),
+            segment(false, ".", 5006, 107, b""),
+            segment(false, "F.", 5006, 107, b""),
+            segment(true, ".", 107, 5007, b""),
+        ];
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        for (i, datagram) in capture.iter().enumerate() {
+            writer
+                .write(Duration::from_millis(i as u64), datagram)
+                .unwrap();
+        }
+        let file = writer.into_inner();
+
+        let report = replay(PcapReader::new(&file[..]).unwrap()).unwrap();
+        report.assert_matches();
+        assert_eq!(report.sent[2].1.len, 5);
+    }
+
+    #[test]
+    fn replays_what_the_stack_recorded() {
+        let path = std::env::temp_dir().join(format!("replay-{}.pcap", std::process::id()));
+        let mut sim = Simulator::new(7);
+        let client = sim.add_host(CLIENT.into());
+        let server = sim.add_host(SERVER.into());
+        sim.host(server)
+            .set_capture(Some(Capture::create(&path).unwrap()));
+
+        let listener = sim.host(server).bind(80).unwrap();
+        let stream = sim
+            .host(client)
+            .connect(SocketAddrV4::new(SERVER.into(), 80))
+            .unwrap();
+        let mut accepted = None;
+        sim.run_until(Duration::from_secs(1), |sim| {
+            accepted = sim.host(server).accept(listener).ok();
+            accepted.is_some()
+        })
+        .unwrap();
+        let accepted = accepted.unwrap();
+        sim.host(client).write(stream, b"ping").unwrap();
+        sim.host(client).shutdown(stream, Shutdown::Write).unwrap();
+        let mut buf = [0; 4];
+        sim.run_until(Duration::from_secs(1), |sim| {
+            sim.host(server).read(accepted, &mut buf).is_ok()
+        })
+        .unwrap();
+        sim.host(server).write(accepted, b"pong").unwrap();
+        sim.host(server)
+            .shutdown(accepted, Shutdown::Write)
+            .unwrap();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        sim.host(server).set_capture(None);
+
+        let report = replay(PcapReader::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        report.assert_matches();
+    }
+}
@@ -0,0 +1,39 @@
+//! Seeded randomness for the simulator and the fault injector. Not for
+//! anything that has to be unpredictable, see `siphash::random_key` for that.
+
+use std::time::Duration;
+
+/// SplitMix64: tiny, fast and good enough to flip coins.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// True with probability `p`, without drawing a number for `p == 0`.
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    /// Uniformly distributed in `[0, max)`.
+    pub(crate) fn below(&mut self, max: usize) -> usize {
+        (self.next_u64() % max.max(1) as u64) as usize
+    }
+
+    /// Uniformly distributed in `[0, max)`.
+    pub(crate) fn duration_below(&mut self, max: Duration) -> Duration {
+        Duration::from_nanos(self.next_u64() % max.as_nanos().max(1) as u64)
+    }
+}
@@ -0,0 +1,1172 @@
+//! packetdrill-style scripts, pinning down what the stack puts on the wire.
+//!
+//! A [`Script`] runs a stack at 192.0.2.1 on a virtual clock and plays the
+//! peer at 192.0.2.2 itself: it injects segments at given times, expects the
+//! stack to answer with segments matching a pattern, and calls the socket API
+//! in between.
+//!
+//! ```ignore
+//! Script::new()
+//!     .bind(80)
+//!     .inject(seg("S").seq(0).mss(1000))
+//!     .expect(seg("S.").seq(0).ack(1).mss(1460))
+//!     .at(10)
+//!     .inject(seg(".").seq(1).ack(1))
+//!     .accept();
+//! ```
+//!
+//! As in packetdrill, the stack's sequence numbers are relative to its
+//! initial one, in the segments it sends as well as in the acknowledgment
+//! numbers the script injects; the peer's are as the script writes them.
+//! Times are in milliseconds since the start of the script, and segments
+//! are expected within [`TOLERANCE`] of the time they're expected at.
+
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4HeaderSlice, PacketBuilder, TcpHeaderSlice};
+
+use crate::{
+    polled::{ListenerHandle, PolledInterface, StreamHandle},
+    sim::SimDevice,
+    stream, tcp, tfo,
+};
+
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+// Port of the peer for connections it opens.
+const REMOTE_PORT: u16 = 4000;
+const DEFAULT_WINDOW: u16 = 65535;
+/// How far a segment may be sent from the time it's expected at.
+pub(crate) const TOLERANCE: Duration = Duration::from_millis(5);
+// Timer runs between two steps before the stack is considered stuck.
+const MAX_POLLS: usize = 10_000;
+
+/// A segment to inject, or a pattern for one to expect.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Seg {
+    flags: &'static str,
+    seq: Option<u32>,
+    ack: Option<u32>,
+    win: Option<u16>,
+    mss: Option<u16>,
+    cookie: Option<Vec<u8>>,
+    data: Option<Vec<u8>>,
+    len: Option<usize>,
+}
+
+/// A segment with `flags` as tcpdump shows them: S, F, R and P, then `.`
+/// for ACK. Injected, unset fields are 0, without data and with a 65535
+/// byte window. Expected, unset fields match anything except the length,
+/// which is 0 unless given.
+pub(crate) fn seg(flags: &'static str) -> Seg {
+    Seg {
+        flags,
+        ..Default::default()
+    }
+}
+
+impl Seg {
+    pub(crate) fn seq(self, seq: u32) -> Self {
+        Seg {
+            seq: Some(seq),
+            ..self
+        }
+    }
+
+    pub(crate) fn ack(self, ack: u32) -> Self {
+        Seg {
+            ack: Some(ack),
+            ..self
+        }
+    }
+
+    pub(crate) fn win(self, win: u16) -> Self {
+        Seg {
+            win: Some(win),
+            ..self
+        }
+    }
+
+    pub(crate) fn mss(self, mss: u16) -> Self {
+        Seg {
+            mss: Some(mss),
+            ..self
+        }
+    }
+
+    /// A Fast Open option carrying `cookie`, empty for a cookie request.
+    pub(crate) fn cookie(self, cookie: &[u8]) -> Self {
+        Seg {
+            cookie: Some(cookie.to_vec()),
+            ..self
+        }
+    }
+
+    pub(crate) fn data(self, data: &[u8]) -> Self {
+        Seg {
+            data: Some(data.to_vec()),
+            ..self
+        }
+    }
+
+    /// For expected segments, any data of length `len`.
+    pub(crate) fn len(self, len: usize) -> Self {
+        Seg {
+            len: Some(len),
+            ..self
+        }
+    }
+
+    fn has(&self, flag: char) -> bool {
+        self.flags.contains(flag)
+    }
+}
+
+// A segment the stack sent, sequence numbers made relative.
+struct Sent {
+    at: Duration,
+    flags: String,
+    seq: u32,
+    ack: u32,
+    win: u16,
+    mss: Option<u16>,
+    cookie: Option<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+impl Sent {
+    fn matches(&self, pattern: &Seg) -> bool {
+        let flags = |flags: &str| {
+            let mut flags: Vec<_> = flags.chars().filter(|&flag| flag != 'P').collect();
+            flags.sort_unstable();
+            flags
+        };
+        let len = pattern
+            .data
+            .as_ref()
+            .map(Vec::len)
+            .or(pattern.len)
+            .unwrap_or(0);
+        flags(&self.flags) == flags(pattern.flags)
+            && pattern.seq.is_none_or(|seq| seq == self.seq)
+            && pattern.ack.is_none_or(|ack| ack == self.ack)
+            && pattern.win.is_none_or(|win| win == self.win)
+            && pattern.mss.is_none_or(|mss| Some(mss) == self.mss)
+            && pattern
+                .cookie
+                .as_ref()
+                .is_none_or(|cookie| Some(cookie) == self.cookie.as_ref())
+            && pattern.data.as_ref().is_none_or(|data| *data == self.data)
+            && len == self.data.len()
+    }
+}
+
+impl fmt::Display for Sent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}ms [{}] seq {} ack {} win {}",
+            self.at.as_millis(),
+            self.flags,
+            self.seq,
+            self.ack,
+            self.win
+        )?;
+        if let Some(mss) = self.mss {
+            write!(f, " <mss {}>", mss)?;
+        }
+        if let Some(cookie) = &self.cookie {
+            write!(f, " <tfo {:02x?}>", cookie)?;
+        }
+        write!(f, " length {}", self.data.len())
+    }
+}
+
+/// A stack and the peer it talks to, see the module documentation. Every
+/// step panics if the stack doesn't behave, pointing at the step.
+pub(crate) struct Script {
+    interface: PolledInterface<SimDevice>,
+    epoch: Instant,
+    // Time of the script.
+    now: Instant,
+    // Time of the stack, ahead of the script while waiting for a segment.
+    clock: Instant,
+    local_port: Option<u16>,
+    remote_port: u16,
+    // Initial sequence number of the stack, once it sent a SYN.
+    isn: Option<u32>,
+    listener: Option<ListenerHandle>,
+    stream: Option<StreamHandle>,
+    sent: VecDeque<Sent>,
+}
+
+impl Script {
+    pub(crate) fn new() -> Self {
+        Script::with_config(Default::default())
+    }
+
+    /// A stack setting up its connections with `config`.
+    pub(crate) fn with_config(config: tcp::Config) -> Self {
+        let epoch = Instant::now();
+        let device = SimDevice::default();
+        Script {
+            interface: PolledInterface::seeded_with_config(device, LOCAL_ADDR, config, 0, epoch),
+            epoch,
+            now: epoch,
+            clock: epoch,
+            local_port: None,
+            remote_port: REMOTE_PORT,
+            isn: None,
+            listener: None,
+            stream: None,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Moves on to `ms` into the script, running the stack's timers on the
+    /// way.
+    #[track_caller]
+    pub(crate) fn at(&mut self, ms: u64) -> &mut Self {
+        let at = self.epoch + Duration::from_millis(ms);
+        assert!(at >= self.now, "time goes backwards at {}ms", ms);
+        self.run_until(at);
+        self.now = at;
+        self
+    }
+
+    /// Hands `segment` to the stack, from the peer.
+    #[track_caller]
+    pub(crate) fn inject(&mut self, segment: Seg) -> &mut Self {
+        let local_port = self
+            .local_port
+            .expect("Nothing to inject into before bind or connect");
+        let mut builder = PacketBuilder::ipv4(REMOTE_ADDR.octets(), LOCAL_ADDR.octets(), 64).tcp(
+            self.remote_port,
+            local_port,
+            segment.seq.unwrap_or(0),
+            segment.win.unwrap_or(DEFAULT_WINDOW),
+        );
+        if segment.has('S') {
+            builder = builder.syn();
+        }
+        if segment.has('F') {
+            builder = builder.fin();
+        }
+        if segment.has('R') {
+            builder = builder.rst();
+        }
+        if segment.has('P') {
+            builder = builder.psh();
+        }
+        if segment.has('.') {
+            let ack = segment.ack.unwrap_or(0);
+            builder = builder.ack(ack.wrapping_add(self.isn.unwrap_or(0)));
+        }
+        let mut options = Vec::new();
+        if let Some(mss) = segment.mss {
+            options.extend([2, 4]);
+            options.extend(mss.to_be_bytes());
+        }
+        if let Some(cookie) = &segment.cookie {
+            options.extend([tfo::OPTION_KIND, cookie.len() as u8 + 2]);
+            options.extend(cookie);
+        }
+        if !options.is_empty() {
+            // Padded with end of option list.
+            options.resize(options.len().next_multiple_of(4), 0);
+            builder = builder.options_raw(&options).expect("Options fit");
+        }
+        let data = segment.data.unwrap_or_default();
+        let mut datagram = Vec::new();
+        builder
+            .write(&mut datagram, &data)
+            .expect("Writing to a Vec");
+        self.interface.device_mut().inbox.push_back(datagram);
+        self.poll();
+        self
+    }
+
+    /// The next segment the stack sends has to match `pattern`, sent now.
+    #[track_caller]
+    pub(crate) fn expect(&mut self, pattern: Seg) -> &mut Self {
+        if self.sent.is_empty() {
+            self.run_until(self.now + TOLERANCE);
+        }
+        let now = self.now - self.epoch;
+        let Some(sent) = self.sent.pop_front() else {
+            panic!(
+                "at {}ms: expected {:?}, nothing sent",
+                now.as_millis(),
+                pattern
+            );
+        };
+        if !sent.matches(&pattern) || sent.at.abs_diff(now) > TOLERANCE {
+            panic!(
+                "at {}ms: expected {:?}, sent {}",
+                now.as_millis(),
+                pattern,
+                sent
+            );
+        }
+        self
+    }
+
+    /// The stack doesn't send anything, now or within [`TOLERANCE`].
+    #[track_caller]
+    pub(crate) fn expect_nothing(&mut self) -> &mut Self {
+        self.run_until(self.now + TOLERANCE);
+        if let Some(sent) = self.sent.front() {
+            panic!(
+                "at {}ms: expected nothing, sent {}",
+                (self.now - self.epoch).as_millis(),
+                sent
+            );
+        }
+        self
+    }
+
+    /// Has the stack answer with SYN cookies once `backlog` handshakes are
+    /// under way.
+    pub(crate) fn syn_backlog(&mut self, backlog: usize) -> &mut Self {
+        self.interface.set_syn_backlog(backlog);
+        self
+    }
+
+    /// Lets the stack issue Fast Open cookies and take data on SYNs.
+    pub(crate) fn fast_open(&mut self) -> &mut Self {
+        self.interface.set_fast_open(true);
+        self
+    }
+
+    /// Rotates the stack's Fast Open key to `key`.
+    pub(crate) fn fast_open_key(&mut self, key: [u8; 16]) -> &mut Self {
+        self.interface.set_fast_open_key(key);
+        self
+    }
+
+    /// The Fast Open cookie the stack issues to the peer right now.
+    pub(crate) fn cookie(&self) -> Vec<u8> {
+        self.interface.coordinator().fast_open().cookie(REMOTE_ADDR)
+    }
+
+    /// Injects from the peer's `port` from now on, as another connection.
+    pub(crate) fn peer_port(&mut self, port: u16) -> &mut Self {
+        self.remote_port = port;
+        self
+    }
+
+    /// Injects into `port` from now on, say one nobody listens on.
+    pub(crate) fn port(&mut self, port: u16) -> &mut Self {
+        self.local_port = Some(port);
+        self
+    }
+
+    #[track_caller]
+    pub(crate) fn bind(&mut self, port: u16) -> &mut Self {
+        self.bind_with_backlog(port, stream::DEFAULT_BACKLOG)
+    }
+
+    #[track_caller]
+    pub(crate) fn bind_with_backlog(&mut self, port: u16, backlog: usize) -> &mut Self {
+        let listener = self.interface.bind_with_backlog(port, backlog).unwrap();
+        self.listener = Some(listener);
+        self.local_port = Some(port);
+        self
+    }
+
+    /// Stops listening, resetting the connections nobody accepted.
+    pub(crate) fn unbind(&mut self) -> &mut Self {
+        let listener = self.listener.take().expect("Unbinding before bind");
+        self.interface.unbind(listener);
+        self.poll();
+        self
+    }
+
+    /// A connection has to be waiting to be accepted.
+    #[track_caller]
+    pub(crate) fn accept(&mut self) -> &mut Self {
+        let listener = self.listener.expect("Accepting before bind");
+        match self.interface.accept(listener) {
+            Ok(stream) => self.stream = Some(stream),
+            Err(e) => panic!("at {}ms: accept failed: {}", self.ms(), e),
+        }
+        self.poll();
+        self
+    }
+
+    /// No connection is waiting to be accepted.
+    #[track_caller]
+    pub(crate) fn accept_nothing(&mut self) -> &mut Self {
+        let listener = self.listener.expect("Accepting before bind");
+        if let Ok(stream) = self.interface.accept(listener) {
+            panic!("at {}ms: accepted {:?}", self.ms(), stream);
+        }
+        self
+    }
+
+    /// Opens a connection to the peer's `port`, the SYN isn't expected yet.
+    #[track_caller]
+    pub(crate) fn connect(&mut self, port: u16) -> &mut Self {
+        self.remote_port = port;
+        let addr = SocketAddrV4::new(REMOTE_ADDR, port);
+        self.stream = Some(self.interface.connect(addr).unwrap());
+        self.poll();
+        self
+    }
+
+    /// Whether the connection opened by `connect` is established yet,
+    /// failing with the error of a refused one.
+    #[track_caller]
+    pub(crate) fn is_connected(&mut self) -> io::Result<bool> {
+        let stream = self.stream();
+        self.interface.is_connected(stream)
+    }
+
+    #[track_caller]
+    pub(crate) fn write(&mut self, data: &[u8]) -> &mut Self {
+        let stream = self.stream();
+        match self.interface.write(stream, data) {
+            Ok(n) => assert_eq!(n, data.len(), "at {}ms: short write", self.ms()),
+            Err(e) => panic!("at {}ms: write failed: {}", self.ms(), e),
+        }
+        self.poll();
+        self
+    }
+
+    /// Exactly `data` is waiting to be read, empty for the end of the
+    /// stream.
+    #[track_caller]
+    pub(crate) fn read(&mut self, data: &[u8]) -> &mut Self {
+        let stream = self.stream();
+        let mut buf = vec![0; data.len() + 1];
+        match self.interface.read(stream, &mut buf) {
+            Ok(n) => assert_eq!(&buf[..n], data, "at {}ms: read", self.ms()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && data.is_empty() => {
+                panic!("at {}ms: read would block, expected EOF", self.ms())
+            }
+            Err(e) => panic!("at {}ms: read failed: {}", self.ms(), e),
+        }
+        self.poll();
+        self
+    }
+
+    /// Reading fails with `kind`, `WouldBlock` if there's nothing to read.
+    #[track_caller]
+    pub(crate) fn read_error(&mut self, kind: io::ErrorKind) -> &mut Self {
+        let stream = self.stream();
+        match self.interface.read(stream, &mut [0; 64]) {
+            Ok(n) => panic!("at {}ms: read {} bytes, expected {:?}", self.ms(), n, kind),
+            Err(e) => assert_eq!(e.kind(), kind, "at {}ms: read", self.ms()),
+        }
+        self
+    }
+
+    #[track_caller]
+    pub(crate) fn shutdown(&mut self, how: Shutdown) -> &mut Self {
+        let stream = self.stream();
+        self.interface.shutdown(stream, how).unwrap();
+        self.poll();
+        self
+    }
+
+    /// Resets the connection.
+    #[track_caller]
+    pub(crate) fn abort(&mut self) -> &mut Self {
+        let stream = self.stream.take().expect("No connection to abort");
+        self.interface.abort(stream);
+        self.poll();
+        self
+    }
+
+    #[track_caller]
+    fn stream(&self) -> StreamHandle {
+        self.stream.expect("No connection, accept or connect first")
+    }
+
+    fn ms(&self) -> u128 {
+        (self.now - self.epoch).as_millis()
+    }
+
+    // Runs the stack until `until`, unless it's already past that.
+    fn run_until(&mut self, until: Instant) {
+        for _ in 0..MAX_POLLS {
+            match self.interface.poll_at() {
+                Some(at) if at <= until && at >= self.clock => {
+                    self.clock = at;
+                    self.poll();
+                }
+                Some(at) if at < self.clock => self.poll(),
+                _ => {
+                    self.clock = self.clock.max(until);
+                    self.poll();
+                    return;
+                }
+            }
+        }
+        panic!("stack keeps asking to be polled");
+    }
+
+    // Lets the stack handle what it got and collects what it sent.
+    fn poll(&mut self) {
+        self.clock = self.clock.max(self.now);
+        self.interface
+            .poll_once(self.clock)
+            .expect("Simulated devices don't fail");
+        let at = self.clock - self.epoch;
+        let sent: Vec<_> = self.interface.device_mut().outbox.drain(..).collect();
+        for datagram in sent {
+            let ip_header = Ipv4HeaderSlice::from_slice(&datagram).unwrap();
+            let tcp_header =
+                TcpHeaderSlice::from_slice(&datagram[ip_header.slice().len()..]).unwrap();
+            if tcp_header.syn() {
+                self.isn = Some(tcp_header.sequence_number());
+                self.local_port = Some(tcp_header.source_port());
+            }
+            let mut flags = String::new();
+            for (set, flag) in [
+                (tcp_header.syn(), 'S'),
+                (tcp_header.fin(), 'F'),
+                (tcp_header.rst(), 'R'),
+                (tcp_header.psh(), 'P'),
+                (tcp_header.ack(), '.'),
+            ] {
+                if set {
+                    flags.push(flag);
+                }
+            }
+            let options = tcp::SegmentOptions::parse(tcp_header.options());
+            let ack = if tcp_header.ack() {
+                tcp_header.acknowledgment_number()
+            } else {
+                0
+            };
+            let payload = ip_header.slice().len() + tcp_header.slice().len();
+            self.sent.push_back(Sent {
+                at,
+                flags,
+                seq: tcp_header
+                    .sequence_number()
+                    .wrapping_sub(self.isn.unwrap_or(0)),
+                ack,
+                win: tcp_header.window_size(),
+                mss: options.mss,
+                cookie: options.fast_open.map(<[u8]>::to_vec),
+                data: datagram[payload..].to_vec(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A connection accepted on port 80, both sides at sequence number 1 and
+    // an RTT of 10ms, hence a retransmission timeout of 200ms.
+    fn established() -> Script {
+        let mut script = Script::new();
+        script
+            .bind(80)
+            .inject(seg("S").seq(0).mss(1000))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(10)
+            .inject(seg(".").seq(1).ack(1))
+            .accept();
+        script
+    }
+
+    // RFC 9293, section 3.3.2: the state diagram.
+
+    #[test]
+    fn passive_open() {
+        Script::new()
+            .bind(80)
+            .inject(seg("S").seq(0).mss(1000))
+            .expect(seg("S.").seq(0).ack(1).win(65535).mss(1460))
+            .at(10)
+            .inject(seg(".").seq(1).ack(1))
+            .accept()
+            .expect_nothing();
+    }
+
+    #[test]
+    fn active_open() {
+        let mut script = Script::new();
+        script
+            .connect(8080)
+            .expect(seg("S").seq(0).mss(1460))
+            .at(10)
+            .inject(seg("S.").seq(0).ack(1).mss(1000))
+            .expect(seg(".").seq(1).ack(1));
+        assert!(script.is_connected().unwrap());
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        let mut script = Script::new();
+        script
+            .connect(8080)
+            .expect(seg("S").seq(0))
+            .at(10)
+            .inject(seg("S").seq(0).mss(1000))
+            .expect(seg("S.").seq(0).ack(1).mss(1460));
+        assert!(!script.is_connected().unwrap());
+        script
+            .at(20)
+            .inject(seg(".").seq(1).ack(1))
+            .write(b"hello")
+            .expect(seg(".").seq(1).ack(1).data(b"hello"));
+        assert!(script.is_connected().unwrap());
+    }
+
+    #[test]
+    fn data_both_ways() {
+        established()
+            .at(20)
+            .inject(seg("P.").seq(1).ack(1).data(b"ping"))
+            .expect(seg(".").seq(1).ack(5))
+            .read(b"ping")
+            .write(b"pong")
+            .expect(seg(".").seq(1).ack(5).data(b"pong"))
+            .at(30)
+            .inject(seg(".").seq(5).ack(5))
+            .expect_nothing();
+    }
+
+    #[test]
+    fn writes_are_split_at_the_peer_mss() {
+        established()
+            .at(20)
+            .write(&[7; 1024])
+            .expect(seg(".").seq(1).ack(1).len(1000))
+            .expect(seg(".").seq(1001).ack(1).len(24));
+    }
+
+    #[test]
+    fn peer_sequence_numbers_wrap() {
+        Script::new()
+            .bind(80)
+            .inject(seg("S").seq(u32::MAX - 1))
+            .expect(seg("S.").seq(0).ack(u32::MAX))
+            .at(10)
+            .inject(seg(".").seq(u32::MAX).ack(1))
+            .accept()
+            .inject(seg(".").seq(u32::MAX).ack(1).data(b"wrap"))
+            .expect(seg(".").seq(1).ack(3))
+            .inject(seg("F.").seq(3).ack(1))
+            .expect(seg(".").seq(1).ack(4))
+            .read(b"wrap")
+            .read(b"");
+    }
+
+    #[test]
+    fn zero_mss_is_raised_to_the_minimum() {
+        let mut script = Script::new();
+        script
+            .bind(80)
+            .inject(seg("S").seq(0).mss(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(10)
+            .inject(seg(".").seq(1).ack(1))
+            .accept()
+            .at(20)
+            .write(&[7; 100])
+            .expect(seg(".").seq(1).ack(1).len(88))
+            .expect(seg(".").seq(89).ack(1).len(12));
+    }
+
+    #[test]
+    fn passive_close() {
+        established()
+            .at(20)
+            .inject(seg("F.").seq(1).ack(1))
+            .expect(seg(".").seq(1).ack(2))
+            .read(b"")
+            .shutdown(Shutdown::Write)
+            .expect(seg("F.").seq(1).ack(2))
+            .at(30)
+            .inject(seg(".").seq(2).ack(2))
+            .expect_nothing();
+    }
+
+    #[test]
+    fn active_close() {
+        established()
+            .at(20)
+            .shutdown(Shutdown::Write)
+            .expect(seg("F.").seq(1).ack(1))
+            .at(30)
+            .inject(seg(".").seq(1).ack(2))
+            .expect_nothing()
+            .at(40)
+            .inject(seg("F.").seq(1).ack(2))
+            .expect(seg(".").seq(2).ack(2))
+            .read(b"")
+            // In TIME-WAIT, a retransmitted FIN is acknowledged again.
+            .at(1040)
+            .inject(seg("F.").seq(1).ack(2))
+            .expect(seg(".").seq(2).ack(2));
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        established()
+            .at(20)
+            .shutdown(Shutdown::Write)
+            .expect(seg("F.").seq(1).ack(1))
+            .inject(seg("F.").seq(1).ack(1))
+            .expect(seg(".").seq(2).ack(2))
+            .at(30)
+            .inject(seg(".").seq(2).ack(2))
+            .expect_nothing()
+            .read(b"");
+    }
+
+    #[test]
+    fn syn_ack_is_retransmitted() {
+        Script::new()
+            .bind(80)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(1000)
+            .expect(seg("S.").seq(0).ack(1))
+            .at(3000)
+            .expect(seg("S.").seq(0).ack(1))
+            .at(3500)
+            .inject(seg(".").seq(1).ack(1))
+            .accept()
+            .expect_nothing();
+    }
+
+    // Until the application accepts the connection waiting in the full
+    // accept queue, SYNs are dropped. The peer's retransmission gets in.
+    #[test]
+    fn syn_is_dropped_while_the_accept_queue_is_full() {
+        Script::new()
+            .bind_with_backlog(80, 1)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(10)
+            .inject(seg(".").seq(1).ack(1))
+            .peer_port(4001)
+            .inject(seg("S").seq(0))
+            .expect_nothing()
+            .at(1000)
+            .inject(seg("S").seq(0))
+            .expect_nothing()
+            .accept()
+            .at(3000)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(3010)
+            .inject(seg(".").seq(1).ack(1))
+            .accept();
+    }
+
+    #[test]
+    fn syn_retransmissions_give_up() {
+        let mut script = Script::new();
+        script.connect(8080).expect(seg("S").seq(0));
+        for at in [1000, 3000, 7000, 15000, 31000] {
+            script.at(at).expect(seg("S").seq(0));
+        }
+        script.at(63000).expect_nothing();
+        assert_eq!(
+            script.is_connected().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn data_retransmissions_give_up() {
+        let mut script = established();
+        script
+            .at(20)
+            .write(b"hello")
+            .expect(seg("P.").seq(1).ack(1).data(b"hello"))
+            .at(3_600_000)
+            .read_error(io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn data_is_retransmitted_with_backoff() {
+        established()
+            .at(100)
+            .write(b"hello")
+            .expect(seg(".").seq(1).ack(1).data(b"hello"))
+            .at(300)
+            .expect(seg(".").seq(1).ack(1).data(b"hello"))
+            .at(700)
+            .expect(seg(".").seq(1).ack(1).data(b"hello"))
+            .at(800)
+            .inject(seg(".").seq(1).ack(6))
+            .at(2000)
+            .expect_nothing();
+    }
+
+    // RFC 9293, section 3.10.7.4: which segments are acceptable.
+
+    #[test]
+    fn data_beyond_the_window_is_acknowledged_and_dropped() {
+        established()
+            .at(20)
+            .inject(seg(".").seq(70000).ack(1).data(b"far"))
+            .expect(seg(".").seq(1).ack(1))
+            .read_error(io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn duplicate_data_is_acknowledged_and_dropped() {
+        established()
+            .at(20)
+            .inject(seg(".").seq(1).ack(1).data(b"once"))
+            .expect(seg(".").seq(1).ack(5))
+            .inject(seg(".").seq(1).ack(1).data(b"once"))
+            .expect(seg(".").seq(1).ack(5))
+            .read(b"once");
+    }
+
+    #[test]
+    fn overlapping_data_is_trimmed() {
+        established()
+            .at(20)
+            .inject(seg(".").seq(1).ack(1).data(b"abc"))
+            .expect(seg(".").seq(1).ack(4))
+            .inject(seg(".").seq(2).ack(1).data(b"bcdef"))
+            .expect(seg(".").seq(1).ack(7))
+            .read(b"abcdef");
+    }
+
+    #[test]
+    fn data_after_a_hole_waits_for_retransmission() {
+        established()
+            .at(20)
+            .inject(seg(".").seq(4).ack(1).data(b"def"))
+            .expect(seg(".").seq(1).ack(1))
+            .read_error(io::ErrorKind::WouldBlock)
+            .inject(seg(".").seq(1).ack(1).data(b"abc"))
+            .expect(seg(".").seq(1).ack(4))
+            .read(b"abc");
+    }
+
+    #[test]
+    fn segment_without_ack_is_ignored() {
+        established()
+            .at(20)
+            .inject(seg("P").seq(1).data(b"hello"))
+            .expect_nothing()
+            .read_error(io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn ack_of_unsent_data_is_answered() {
+        established()
+            .at(20)
+            .inject(seg(".").seq(1).ack(100))
+            .expect(seg(".").seq(1).ack(1))
+            .write(b"still")
+            .expect(seg(".").seq(1).ack(1).data(b"still"));
+    }
+
+    #[test]
+    fn retransmitted_syn_gets_the_syn_ack_again() {
+        Script::new()
+            .bind(80)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(10)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1));
+    }
+
+    #[test]
+    fn bad_ack_in_syn_received_is_reset() {
+        Script::new()
+            .bind(80)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(10)
+            .inject(seg(".").seq(1).ack(50))
+            .expect(seg("R").seq(50));
+    }
+
+    // RFC 9293, section 3.5.2, and RFC 5961: resets.
+
+    #[test]
+    fn exact_rst_resets_the_connection() {
+        established()
+            .at(20)
+            .inject(seg("R").seq(1))
+            .expect_nothing()
+            .read_error(io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn inexact_rst_gets_a_challenge_ack() {
+        established()
+            .at(20)
+            .inject(seg("R").seq(100))
+            .expect(seg(".").seq(1).ack(1))
+            .write(b"alive")
+            .expect(seg(".").seq(1).ack(1).data(b"alive"));
+    }
+
+    #[test]
+    fn rst_outside_the_window_is_ignored() {
+        established()
+            .at(20)
+            .inject(seg("R").seq(100_000))
+            .expect_nothing()
+            .write(b"alive")
+            .expect(seg(".").seq(1).ack(1).data(b"alive"));
+    }
+
+    #[test]
+    fn syn_on_an_established_connection_gets_a_challenge_ack() {
+        established()
+            .at(20)
+            .inject(seg("S").seq(1))
+            .expect(seg(".").seq(1).ack(1));
+    }
+
+    #[test]
+    fn closed_port_resets() {
+        Script::new()
+            .port(81)
+            .inject(seg("S").seq(0))
+            .expect(seg("R.").seq(0).ack(1))
+            .inject(seg(".").seq(1).ack(0xdead))
+            .expect(seg("R").seq(0xdead))
+            .inject(seg("R").seq(1))
+            .expect_nothing();
+    }
+
+    #[test]
+    fn unbinding_resets_pending_connections() {
+        Script::new()
+            .bind(80)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(10)
+            .inject(seg(".").seq(1).ack(1))
+            .unbind()
+            .expect(seg("R").seq(1))
+            .at(20)
+            .inject(seg("S").seq(100))
+            .expect(seg("R.").ack(101));
+    }
+
+    #[test]
+    fn ack_to_a_listener_resets() {
+        Script::new()
+            .bind(80)
+            .inject(seg(".").seq(1).ack(0xdead))
+            .expect(seg("R").seq(0xdead));
+    }
+
+    #[test]
+    fn rst_refuses_a_connection() {
+        let mut script = Script::new();
+        script
+            .connect(8080)
+            .expect(seg("S").seq(0))
+            .at(10)
+            .inject(seg("R.").seq(0).ack(1))
+            .expect_nothing();
+        assert_eq!(
+            script.is_connected().unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn rst_with_a_bad_ack_is_ignored_in_syn_sent() {
+        let mut script = Script::new();
+        script
+            .connect(8080)
+            .expect(seg("S").seq(0))
+            .at(10)
+            .inject(seg("R.").seq(0).ack(50))
+            .expect_nothing()
+            .inject(seg("S.").seq(0).ack(1))
+            .expect(seg(".").seq(1).ack(1));
+        assert!(script.is_connected().unwrap());
+    }
+
+    #[test]
+    fn bad_ack_in_syn_sent_is_reset() {
+        Script::new()
+            .connect(8080)
+            .expect(seg("S").seq(0))
+            .at(10)
+            .inject(seg("S.").seq(0).ack(50))
+            .expect(seg("R").seq(50));
+    }
+
+    #[test]
+    fn abort_sends_rst() {
+        established()
+            .at(20)
+            .abort()
+            .expect(seg("R").seq(1))
+            .at(30)
+            .inject(seg(".").seq(1).ack(1))
+            .expect(seg("R").seq(1));
+    }
+
+    // RFC 7413, section 4.1.2.
+    #[test]
+    fn fast_open_cookie_request_gets_a_cookie() {
+        let mut script = Script::new();
+        script.fast_open().bind(80);
+        let cookie = script.cookie();
+        script
+            .inject(seg("S").seq(0).cookie(&[]))
+            .expect(seg("S.").seq(0).ack(1).cookie(&cookie));
+    }
+
+    #[test]
+    fn fast_open_data_is_readable_before_the_handshake_completes() {
+        let mut script = Script::new();
+        script.fast_open().bind(80);
+        let cookie = script.cookie();
+        script
+            .inject(seg("S").seq(0).cookie(&cookie).data(b"hello"))
+            .expect(seg("S.").seq(0).ack(6))
+            .accept()
+            .read(b"hello")
+            .at(10)
+            .inject(seg(".").seq(6).ack(1))
+            .write(b"world")
+            .expect(seg("P.").seq(1).ack(6).data(b"world"));
+    }
+
+    // The data is dropped and the peer gets a fresh cookie, it sends the
+    // data again after the handshake.
+    #[test]
+    fn fast_open_data_with_a_bad_cookie_waits_for_the_handshake() {
+        let mut script = Script::new();
+        script.fast_open().bind(80);
+        let cookie = script.cookie();
+        script
+            .inject(seg("S").seq(0).cookie(&[1; 8]).data(b"hello"))
+            .expect(seg("S.").seq(0).ack(1).cookie(&cookie))
+            .accept_nothing()
+            .at(10)
+            .inject(seg(".").seq(1).ack(1))
+            .accept()
+            .inject(seg("P.").seq(1).ack(1).data(b"hello"))
+            .expect(seg(".").seq(1).ack(6))
+            .read(b"hello");
+    }
+
+    #[test]
+    fn fast_open_data_is_ignored_unless_enabled() {
+        let mut script = Script::new();
+        let cookie = script.cookie();
+        script
+            .bind(80)
+            .inject(seg("S").seq(0).cookie(&cookie).data(b"hello"))
+            .expect(seg("S.").seq(0).ack(1))
+            .accept_nothing();
+    }
+
+    #[test]
+    fn fast_open_cookies_outlive_one_key_rotation() {
+        let mut script = Script::new();
+        script.fast_open().bind(80);
+        let cookie = script.cookie();
+        script.fast_open_key([1; 16]);
+        assert_ne!(script.cookie(), cookie);
+        script
+            .inject(seg("S").seq(0).cookie(&cookie).data(b"hello"))
+            .expect(seg("S.").seq(0).ack(6))
+            .accept()
+            .read(b"hello");
+
+        let mut script = Script::new();
+        script.fast_open().bind(80);
+        let cookie = script.cookie();
+        script.fast_open_key([1; 16]).fast_open_key([2; 16]);
+        let fresh = script.cookie();
+        script
+            .inject(seg("S").seq(0).cookie(&cookie).data(b"hello"))
+            .expect(seg("S.").seq(0).ack(1).cookie(&fresh));
+    }
+
+    #[test]
+    fn fast_open_data_beyond_the_window_waits_for_the_handshake() {
+        let mut script = Script::with_config(tcp::Config {
+            recv_buffer_size: 4,
+            ..Default::default()
+        });
+        script.fast_open().bind(80);
+        let cookie = script.cookie();
+        script
+            .inject(seg("S").seq(0).cookie(&cookie).data(b"hello"))
+            .expect(seg("S.").seq(0).ack(5).win(0))
+            .accept()
+            .read(b"hell")
+            .at(10)
+            .inject(seg("P.").seq(5).ack(1).data(b"o"))
+            .expect(seg(".").seq(1).ack(6))
+            .read(b"o");
+    }
+
+    // RFC 4987, section 3.6: the half-open connection stays in the SYN
+    // queue, the next one is answered with a cookie carrying its MSS.
+    #[test]
+    fn syn_cookies_take_over_when_the_backlog_is_full() {
+        let mut script = Script::new();
+        script
+            .syn_backlog(1)
+            .bind(80)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .peer_port(4001)
+            .inject(seg("S").seq(100).mss(600))
+            .expect(seg("S.").seq(0).ack(101))
+            .accept_nothing()
+            .at(10)
+            .inject(seg(".").seq(101).ack(1))
+            .accept()
+            .write(&[7; 1000])
+            .expect(seg(".").seq(1).ack(101).len(536))
+            .expect(seg("P.").seq(537).ack(101).len(464));
+    }
+
+    #[test]
+    fn forged_syn_cookie_is_reset() {
+        let mut script = Script::new();
+        script
+            .syn_backlog(0)
+            .bind(80)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(10)
+            .inject(seg(".").seq(1).ack(2))
+            .expect(seg("R").seq(2))
+            .inject(seg(".").seq(2).ack(1))
+            .expect(seg("R").seq(1))
+            .accept_nothing();
+    }
+
+    #[test]
+    fn expired_syn_cookie_is_reset() {
+        let mut script = Script::new();
+        script
+            .syn_backlog(0)
+            .bind(80)
+            .inject(seg("S").seq(0))
+            .expect(seg("S.").seq(0).ack(1))
+            .at(200_000)
+            .inject(seg(".").seq(1).ack(1))
+            .expect(seg("R").seq(1))
+            .accept_nothing();
+    }
+}
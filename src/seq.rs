@@ -0,0 +1,195 @@
+//! Sequence numbers and their arithmetic modulo 2^32.
+//!
+//! A [`SeqNum`] only adds and subtracts with wrapping, and compares the way
+//! RFC 1982 serial numbers do: `a < b` when `b` is less than 2^31 ahead of
+//! `a`, going around if need be. Numbers exactly 2^31 apart aren't ordered
+//! either way. That isn't a total order, not even a transitive one across
+//! more than half the number space, so `SeqNum` doesn't implement `Ord` and
+//! must not be used as a key for sorted collections.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, AddAssign, Sub},
+};
+
+/// A TCP sequence or acknowledgment number.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct SeqNum(u32);
+
+impl SeqNum {
+    pub(crate) const fn new(n: u32) -> Self {
+        SeqNum(n)
+    }
+
+    /// The number as it goes on the wire.
+    pub(crate) const fn get(self) -> u32 {
+        self.0
+    }
+
+    /// Whether the number is in `[start, start + len)`.
+    pub(crate) fn in_window(self, start: SeqNum, len: u32) -> bool {
+        self - start < len
+    }
+
+    /// Whether the number is in `[start, end)`, which is empty for
+    /// `start == end`.
+    pub(crate) fn in_range(self, start: SeqNum, end: SeqNum) -> bool {
+        self.in_window(start, end - start)
+    }
+}
+
+impl From<u32> for SeqNum {
+    fn from(n: u32) -> Self {
+        SeqNum(n)
+    }
+}
+
+impl fmt::Display for SeqNum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Add<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn add(self, n: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_add(n))
+    }
+}
+
+impl AddAssign<u32> for SeqNum {
+    fn add_assign(&mut self, n: u32) {
+        *self = *self + n;
+    }
+}
+
+impl Sub<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn sub(self, n: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_sub(n))
+    }
+}
+
+/// How far `self` is ahead of `rhs`, going around if need be.
+impl Sub for SeqNum {
+    type Output = u32;
+
+    fn sub(self, rhs: SeqNum) -> u32 {
+        self.0.wrapping_sub(rhs.0)
+    }
+}
+
+impl PartialOrd for SeqNum {
+    fn partial_cmp(&self, other: &SeqNum) -> Option<Ordering> {
+        // From RFC1323:
+        //     TCP determines if a data segment is "old" or "new" by testing
+        //     whether its sequence number is within 2**31 bytes of the left
+        //     edge of the window, and if it is not, discarding the data as
+        //     "old".  To ensure that new data is never mistakenly considered
+        //     old and vice-versa, the left edge of the sender's window has to
+        //     be at most 2**31 away from the right edge of the receiver's
+        //     window.
+        match *self - *other {
+            0 => Some(Ordering::Equal),
+            ahead if ahead < 1 << 31 => Some(Ordering::Greater),
+            ahead if ahead > 1 << 31 => Some(Ordering::Less),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const HALF: u32 = 1 << 31;
+
+    // Any number, a lot of them close to where sequence numbers wrap or
+    // flip from ahead to behind.
+    fn number() -> impl Strategy<Value = u32> {
+        let edge = prop_oneof![
+            Just(0),
+            Just(HALF - 1),
+            Just(HALF),
+            Just(HALF + 1),
+            Just(u32::MAX)
+        ];
+        prop_oneof![
+            edge.clone(),
+            (edge, -1024..1024i32).prop_map(|(n, d)| n.wrapping_add_signed(d)),
+            any::<u32>(),
+        ]
+    }
+
+    fn seq_num() -> impl Strategy<Value = SeqNum> {
+        number().prop_map(SeqNum::new)
+    }
+
+    #[test]
+    fn add_and_sub_wrap() {
+        assert_eq!(SeqNum::new(u32::MAX) + 1, SeqNum::new(0));
+        assert_eq!(SeqNum::new(0) - 1, SeqNum::new(u32::MAX));
+        assert_eq!(SeqNum::new(5) - SeqNum::new(u32::MAX - 4), 10);
+    }
+
+    proptest! {
+        #[test]
+        fn add_and_sub_are_inverses(a in seq_num(), d in number()) {
+            prop_assert_eq!((a + d) - a, d);
+            prop_assert_eq!((a + d) - d, a);
+            prop_assert_eq!(a - (a - d), d);
+            let mut b = a;
+            b += d;
+            prop_assert_eq!(b, a + d);
+        }
+
+        #[test]
+        fn later_numbers_compare_greater(a in seq_num(), d in number()) {
+            let b = a + d;
+            match d {
+                0 => prop_assert_eq!(a.partial_cmp(&b), Some(Ordering::Equal)),
+                1..HALF => {
+                    prop_assert!(a < b, "{} < {}", a, b);
+                    prop_assert_eq!(b.partial_cmp(&a), Some(Ordering::Greater));
+                }
+                HALF => prop_assert_eq!(a.partial_cmp(&b), None),
+                _ => {
+                    prop_assert!(b < a, "{} < {}", b, a);
+                    prop_assert_eq!(a.partial_cmp(&b), Some(Ordering::Greater));
+                }
+            }
+        }
+
+        #[test]
+        fn comparison_is_antisymmetric(a in seq_num(), d in number()) {
+            let b = a + d;
+            let reversed = b.partial_cmp(&a).map(Ordering::reverse);
+            prop_assert_eq!(a.partial_cmp(&b), reversed);
+        }
+
+        #[test]
+        fn windows_contain_their_start_but_not_their_end(start in seq_num(), len in number()) {
+            prop_assert_eq!(start.in_window(start, len), len > 0);
+            prop_assert!(!(start + len).in_window(start, len));
+            prop_assert!(!(start - 1).in_window(start, len) || len == u32::MAX);
+            if len > 0 {
+                prop_assert!((start + (len - 1)).in_window(start, len));
+            }
+        }
+
+        #[test]
+        fn ranges_agree_with_comparisons(start in seq_num(), len in number()) {
+            // Within half the number space, where comparisons are sound.
+            let len = len % HALF;
+            let end = start + len;
+            for offset in [0, 1, len / 2, len.wrapping_sub(1), len, len + 1] {
+                let n = start + offset;
+                prop_assert_eq!(n.in_range(start, end), start <= n && n < end, "{}", n);
+            }
+        }
+    }
+}
@@ -0,0 +1,527 @@
+//! A deterministic network for [`PolledInterface`]s to talk over.
+//!
+//! The [`Simulator`] wires any number of hosts to one simulated [`Link`] and
+//! runs them on a virtual clock: time jumps straight to the next delivery or
+//! timer, so a 60 second TIME-WAIT is over in no time. Every random decision,
+//! those of the link as well as the secrets of the hosts, comes from a single
+//! seed. The same seed and the same application code make the same run: a
+//! simulator dropped while panicking prints its seed, and
+//! [`Simulator::from_env`] takes it from `SIM_SEED` to replay the run, or
+//! picks a fresh one to explore others.
+//!
+//! ```no_run
+//! # use std::{net::Ipv4Addr, time::Duration};
+//! # use user_space_tcp::sim::{Link, Simulator};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut sim = Simulator::from_env();
+//! sim.set_link(Link { loss: 0.1, ..Default::default() });
+//! let client = sim.add_host(Ipv4Addr::new(10, 0, 0, 1));
+//! let server = sim.add_host(Ipv4Addr::new(10, 0, 0, 2));
+//! let listener = sim.host(server).bind(80)?;
+//! let stream = sim.host(client).connect("10.0.0.2:80".parse()?)?;
+//! sim.run_until(Duration::from_secs(10), |sim| sim.host(server).accept(listener).is_ok())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    cmp::{self, Reverse},
+    collections::{BinaryHeap, VecDeque},
+    env, io,
+    net::Ipv4Addr,
+    num::NonZeroU64,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    device::{Capabilities, NetDevice},
+    polled::PolledInterface,
+    rng::Rng,
+    siphash,
+};
+
+const SEED_VAR: &str = "SIM_SEED";
+const MTU: usize = 1500;
+// Steps a single run may take, a simulation that keeps going without the
+// clock moving is stuck.
+const MAX_STEPS: usize = 1_000_000;
+
+/// What the link does to datagrams, the same in every direction.
+#[derive(Clone, Debug)]
+pub struct Link {
+    /// One-way delay of every datagram.
+    pub latency: Duration,
+    /// Bytes per second each host can send, `None` for no limit.
+    pub bandwidth: Option<NonZeroU64>,
+    /// Probability that a datagram is lost.
+    pub loss: f64,
+    /// Probability that a datagram is held back by up to another `latency`
+    /// (at least a millisecond), letting later ones overtake it.
+    pub reorder: f64,
+    /// Probability that a datagram arrives twice.
+    pub duplicate: f64,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Link {
+            latency: Duration::from_millis(1),
+            bandwidth: None,
+            loss: 0.0,
+            reorder: 0.0,
+            duplicate: 0.0,
+        }
+    }
+}
+
+/// What happened on the link so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub sent: usize,
+    pub delivered: usize,
+    /// Lost to [`Link::loss`] or sent to an address no host has.
+    pub lost: usize,
+    pub reordered: usize,
+    pub duplicated: usize,
+}
+
+/// A host of a [`Simulator`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HostId(usize);
+
+/// Device of a simulated host, queues the simulator fills and drains.
+#[derive(Default)]
+pub struct SimDevice {
+    pub(crate) inbox: VecDeque<Vec<u8>>,
+    pub(crate) outbox: VecDeque<Vec<u8>>,
+}
+
+impl NetDevice for SimDevice {
+    fn recv(&mut self, buf: &mut [u8], _timeout: Duration) -> io::Result<Option<usize>> {
+        // Nothing to wait for on a virtual clock, the simulator polls a host
+        // once something arrived for it.
+        let datagram = match self.inbox.pop_front() {
+            Some(datagram) => datagram,
+            None => return Ok(None),
+        };
+        let n = cmp::min(datagram.len(), buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok(Some(n))
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.outbox.push_back(datagram.to_vec());
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // The link loses datagrams but never corrupts them.
+        Capabilities::RX_CHECKSUM
+    }
+}
+
+// A datagram on the link: arrival time, position in send order, index of
+// the receiving host and the datagram itself. Reversed for a min-heap.
+type InFlight = Reverse<(Instant, u64, usize, Vec<u8>)>;
+
+struct Host {
+    addr: Ipv4Addr,
+    interface: PolledInterface<SimDevice>,
+    // When the last datagram the host sent is completely on the link.
+    tx_free_at: Instant,
+}
+
+pub struct Simulator {
+    seed: u64,
+    rng: Rng,
+    link: Link,
+    start: Instant,
+    now: Instant,
+    hosts: Vec<Host>,
+    // Datagrams on the link by arrival time, then by when they were sent.
+    in_flight: BinaryHeap<InFlight>,
+    next_id: u64,
+    stats: Stats,
+}
+
+impl Simulator {
+    pub fn new(seed: u64) -> Self {
+        // Any instant does as the origin, only durations from it matter.
+        let start = Instant::now();
+        Simulator {
+            seed,
+            rng: Rng::new(seed),
+            link: Default::default(),
+            start,
+            now: start,
+            hosts: Vec::new(),
+            in_flight: BinaryHeap::new(),
+            next_id: 0,
+            stats: Default::default(),
+        }
+    }
+
+    /// Uses the seed in `SIM_SEED` if set, a fresh one otherwise.
+    pub fn from_env() -> Self {
+        let seed = match env::var(SEED_VAR) {
+            Ok(seed) => seed.parse().expect("SIM_SEED is not a number"),
+            Err(_) => {
+                let key = siphash::random_key();
+                u64::from_le_bytes(key[..8].try_into().unwrap())
+            }
+        };
+        Simulator::new(seed)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn set_link(&mut self, link: Link) {
+        self.link = link;
+    }
+
+    /// Adds a host answering on `addr`, reachable from all the others.
+    pub fn add_host(&mut self, addr: Ipv4Addr) -> HostId {
+        let seed = self.rng.next_u64();
+        let interface = PolledInterface::seeded(SimDevice::default(), addr, seed, self.now);
+        self.hosts.push(Host {
+            addr,
+            interface,
+            tx_free_at: self.now,
+        });
+        HostId(self.hosts.len() - 1)
+    }
+
+    /// The stack of `host`, to bind, connect, read and write through.
+    /// Whatever it queues goes out on the next step.
+    pub fn host(&mut self, host: HostId) -> &mut PolledInterface<SimDevice> {
+        &mut self.hosts[host.0].interface
+    }
+
+    /// The virtual clock.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Virtual time since the simulator was created.
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Advances the clock to the next arrival or timer and lets the hosts
+    /// handle it. `false` if nothing is left to happen.
+    pub fn step(&mut self) -> io::Result<bool> {
+        match self.next_event() {
+            Some(next) => {
+                self.now = cmp::max(self.now, next);
+                self.poll()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Runs `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) -> io::Result<()> {
+        let end = self.now + duration;
+        for _ in 0..MAX_STEPS {
+            match self.next_event() {
+                Some(next) if next <= end => {
+                    self.now = cmp::max(self.now, next);
+                    self.poll()?;
+                }
+                _ => {
+                    self.now = end;
+                    return self.poll();
+                }
+            }
+        }
+        self.stuck()
+    }
+
+    /// Runs until `done` holds, checking it before every step. `false` if
+    /// nothing was left to happen or `limit` of virtual time passed first.
+    pub fn run_until(
+        &mut self,
+        limit: Duration,
+        mut done: impl FnMut(&mut Self) -> bool,
+    ) -> io::Result<bool> {
+        let end = self.now + limit;
+        for _ in 0..MAX_STEPS {
+            if done(self) {
+                return Ok(true);
+            }
+            match self.next_event() {
+                Some(next) if next <= end => {
+                    self.now = cmp::max(self.now, next);
+                    self.poll()?;
+                }
+                _ => return Ok(false),
+            }
+        }
+        self.stuck()
+    }
+
+    fn stuck(&self) -> ! {
+        panic!(
+            "simulation doesn't settle at {:?}, seed {}",
+            self.elapsed(),
+            self.seed
+        );
+    }
+
+    /// When the next datagram arrives or a host's timer fires.
+    fn next_event(&self) -> Option<Instant> {
+        let arrival = self.in_flight.peek().map(|Reverse((at, ..))| *at);
+        self.hosts
+            .iter()
+            .filter_map(|host| host.interface.poll_at())
+            .chain(arrival)
+            .min()
+    }
+
+    // Delivers what arrived by now and runs every host with something to do.
+    fn poll(&mut self) -> io::Result<()> {
+        while let Some(Reverse((at, ..))) = self.in_flight.peek() {
+            if *at > self.now {
+                break;
+            }
+            let Reverse((_, _, to, datagram)) = self.in_flight.pop().unwrap();
+            self.hosts[to]
+                .interface
+                .device_mut()
+                .inbox
+                .push_back(datagram);
+            self.stats.delivered += 1;
+        }
+        for from in 0..self.hosts.len() {
+            let interface = &mut self.hosts[from].interface;
+            let due = interface.poll_at().is_some_and(|at| at <= self.now);
+            if !due && interface.device().inbox.is_empty() {
+                continue;
+            }
+            interface.poll_once(self.now)?;
+            let sent = std::mem::take(&mut interface.device_mut().outbox);
+            for datagram in sent {
+                self.transmit(from, datagram);
+            }
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self, from: usize, datagram: Vec<u8>) {
+        self.stats.sent += 1;
+        let to = datagram
+            .get(16..20)
+            .map(|dst| Ipv4Addr::new(dst[0], dst[1], dst[2], dst[3]))
+            .and_then(|dst| self.hosts.iter().position(|host| host.addr == dst));
+        let to = match to {
+            Some(to) => to,
+            None => {
+                self.stats.lost += 1;
+                return;
+            }
+        };
+
+        // Datagrams queue up behind each other on a slow link.
+        let host = &mut self.hosts[from];
+        let start = cmp::max(self.now, host.tx_free_at);
+        host.tx_free_at = match self.link.bandwidth {
+            Some(bandwidth) => {
+                start
+                    + Duration::from_nanos(datagram.len() as u64 * 1_000_000_000 / bandwidth.get())
+            }
+            None => start,
+        };
+        let mut at = host.tx_free_at + self.link.latency;
+
+        if self.rng.chance(self.link.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        if self.rng.chance(self.link.reorder) {
+            at += self
+                .rng
+                .duration_below(cmp::max(self.link.latency, Duration::from_millis(1)));
+            self.stats.reordered += 1;
+        }
+        if self.rng.chance(self.link.duplicate) {
+            self.push(at, to, datagram.clone());
+            self.stats.duplicated += 1;
+        }
+        self.push(at, to, datagram);
+    }
+
+    fn push(&mut self, at: Instant, to: usize, datagram: Vec<u8>) {
+        self.in_flight
+            .push(Reverse((at, self.next_id, to, datagram)));
+        self.next_id += 1;
+    }
+}
+
+// A failing run is only worth something with the seed to replay it.
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            eprintln!(
+                "simulator seed {}, replay with {}={}",
+                self.seed, SEED_VAR, self.seed
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polled::{ListenerHandle, StreamHandle};
+    use std::net::{Shutdown, SocketAddrV4};
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const PORT: u16 = 80;
+    const SEED: u64 = 41;
+
+    fn connect(sim: &mut Simulator) -> (HostId, StreamHandle, HostId, StreamHandle) {
+        let client = sim.add_host(CLIENT);
+        let server = sim.add_host(SERVER);
+        let listener: ListenerHandle = sim.host(server).bind(PORT).unwrap();
+        let stream = sim
+            .host(client)
+            .connect(SocketAddrV4::new(SERVER, PORT))
+            .unwrap();
+        let mut accepted = None;
+        let connected = sim
+            .run_until(Duration::from_secs(60), |sim| {
+                if accepted.is_none() {
+                    accepted = sim.host(server).accept(listener).ok();
+                }
+                accepted.is_some() && sim.host(client).is_connected(stream).unwrap()
+            })
+            .unwrap();
+        assert!(connected, "no connection after {:?}", sim.elapsed());
+        (client, stream, server, accepted.unwrap())
+    }
+
+    // Sends `data` from client to server over whatever link is set up and
+    // returns what the server read before the end of the stream.
+    fn transfer(sim: &mut Simulator, data: &[u8]) -> Vec<u8> {
+        let (client, stream, server, accepted) = connect(sim);
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        let done = sim
+            .run_until(Duration::from_secs(600), |sim| {
+                while written < data.len() {
+                    match sim.host(client).write(stream, &data[written..]) {
+                        Ok(n) => written += n,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("write failed: {}", e),
+                    }
+                    if written == data.len() {
+                        sim.host(client).shutdown(stream, Shutdown::Write).unwrap();
+                    }
+                }
+                loop {
+                    match sim.host(server).read(accepted, &mut buf) {
+                        Ok(0) => return true,
+                        Ok(n) => received.extend_from_slice(&buf[..n]),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+                        Err(e) => panic!("read failed: {}", e),
+                    }
+                }
+            })
+            .unwrap();
+        assert!(done, "transfer incomplete after {:?}", sim.elapsed());
+        received
+    }
+
+    fn lossy_link() -> Link {
+        Link {
+            latency: Duration::from_millis(20),
+            bandwidth: NonZeroU64::new(1_000_000),
+            loss: 0.1,
+            reorder: 0.1,
+            duplicate: 0.05,
+        }
+    }
+
+    #[test]
+    fn transfer_over_lossy_link() {
+        let mut sim = Simulator::new(SEED);
+        sim.set_link(lossy_link());
+        let data: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(transfer(&mut sim, &data), data);
+        let stats = sim.stats();
+        assert!(stats.lost + stats.reordered + stats.duplicated > 0);
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let run = |seed| {
+            let mut sim = Simulator::new(seed);
+            sim.set_link(lossy_link());
+            transfer(&mut sim, &[7; 20_000]);
+            (sim.elapsed(), sim.stats())
+        };
+        assert_eq!(run(42), run(42));
+    }
+
+    #[test]
+    fn syn_retransmissions_back_off_then_give_up() {
+        let mut sim = Simulator::new(SEED);
+        let client = sim.add_host(CLIENT);
+        // Nobody at SERVER, every SYN is lost.
+        let stream = sim
+            .host(client)
+            .connect(SocketAddrV4::new(SERVER, PORT))
+            .unwrap();
+        let failed = sim
+            .run_until(Duration::from_secs(600), |sim| {
+                sim.host(client).is_connected(stream).is_err()
+            })
+            .unwrap();
+        assert!(failed);
+        // The initial SYN and five retransmissions, 1 + 2 + 4 + 8 + 16 + 32
+        // seconds apart.
+        assert_eq!(sim.stats().sent, 6);
+        assert_eq!(sim.elapsed().as_secs(), 63);
+    }
+
+    #[test]
+    fn time_wait_lasts_two_msl() {
+        let mut sim = Simulator::new(SEED);
+        let (client, stream, server, accepted) = connect(&mut sim);
+        sim.host(client).close(stream);
+        sim.run_until(Duration::from_secs(10), |sim| {
+            matches!(sim.host(server).read(accepted, &mut [0; 1]), Ok(0))
+        })
+        .unwrap();
+        sim.host(server).close(accepted);
+        let closing = sim.elapsed();
+
+        let quiet = sim
+            .run_until(Duration::from_secs(600), |sim| {
+                sim.host(client).poll_at().is_none() && sim.host(server).poll_at().is_none()
+            })
+            .unwrap();
+        assert!(quiet);
+        // The client closed first and holds on to the connection in TIME-WAIT.
+        let time_wait = sim.elapsed() - closing;
+        assert!(
+            (Duration::from_secs(60)..Duration::from_secs(61)).contains(&time_wait),
+            "{:?}",
+            time_wait
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Minimal SipHash-2-4, used to derive cookies from a secret key.
+//!
+//! `std` only exposes SipHash through `RandomState`, which doesn't let us pick
+//! (or rotate) the key, so we carry our own copy of the reference algorithm.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) type Key = [u8; 16];
+
+struct State {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+}
+
+impl State {
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13);
+        self.v1 ^= self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16);
+        self.v3 ^= self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21);
+        self.v3 ^= self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17);
+        self.v1 ^= self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v3 ^= m;
+        self.round();
+        self.round();
+        self.v0 ^= m;
+    }
+}
+
+pub(crate) fn hash(key: &Key, data: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut state = State {
+        v0: k0 ^ 0x736f6d6570736575,
+        v1: k1 ^ 0x646f72616e646f6d,
+        v2: k0 ^ 0x6c7967656e657261,
+        v3: k1 ^ 0x7465646279746573,
+    };
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        state.compress(u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut last = [0u8; 8];
+    let tail = chunks.remainder();
+    last[..tail.len()].copy_from_slice(tail);
+    last[7] = data.len() as u8;
+    state.compress(u64::from_le_bytes(last));
+
+    state.v2 ^= 0xff;
+    for _ in 0..4 {
+        state.round();
+    }
+    state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+}
+
+#[cfg(any(test, feature = "testing"))]
+/// Derives a key from `seed`, the same one every time. `index` tells apart
+/// the keys needed for different purposes.
+pub(crate) fn key_from_seed(seed: u64, index: u64) -> Key {
+    let mut data = [0u8; 17];
+    data[..8].copy_from_slice(&seed.to_le_bytes());
+    data[8..16].copy_from_slice(&index.to_le_bytes());
+    let mut key = [0u8; 16];
+    for (half, chunk) in key.chunks_exact_mut(8).enumerate() {
+        data[16] = half as u8;
+        chunk.copy_from_slice(&hash(&[0; 16], &data).to_le_bytes());
+    }
+    key
+}
+
+/// Generates a fresh key from the process-wide randomness `std` already seeds
+/// `HashMap`s with, mixed with the current time.
+pub(crate) fn random_key() -> Key {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let mut key = [0u8; 16];
+    for half in key.chunks_exact_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(nanos);
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the appendix of the SipHash paper and the reference
+    // implementation's vectors: key 00 01 .. 0f, messages 00 01 ...
+    #[test]
+    fn reference_vectors() {
+        let key: Key = std::array::from_fn(|i| i as u8);
+        let message: Vec<u8> = (0..15).collect();
+        assert_eq!(hash(&key, &[]), 0x726fdb47dd0e0e31);
+        assert_eq!(hash(&key, &message[..8]), 0x93f5f5799a932462);
+        assert_eq!(hash(&key, &message), 0xa129ca6149be45e5);
+    }
+}
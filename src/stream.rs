@@ -1,27 +1,112 @@
-use crate::tcp::{self, Available};
+use crate::{
+    builder::InterfaceBuilder,
+    device::{Capabilities, MemoryDevice, NetDevice},
+    pcap::Capture,
+    poll::{Event, Events, Ready, Socket, SocketKind, Source, Token},
+    siphash, syncookie,
+    tcp::{self, Available},
+    tfo,
+};
 use std::{
-    collections::{hash_map::Entry, HashMap, VecDeque},
+    collections::{btree_map, hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
     io::{self, Read, Write},
-    net::{Ipv4Addr, Shutdown},
-    sync::{Arc, Condvar, Mutex},
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    task::Waker,
+    time::{Duration, Instant},
 };
-use tun_tap::Mode;
 
-const SEND_QUEUE_SIZE: usize = 1024;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+use std::task::{Context, Poll};
+
 //const IP_V4_PROTOCOL: u16 = 0x800;
 const TCP_PROTOCOL: u8 = 0x06;
+// Address our side of tun0 answers on, the kernel's side gets another one
+// from 192.168.0.0/24.
+pub(crate) const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+// Addresses of the two ends of Interface::loopback_pair.
+const LOOPBACK_ADDRS: [Ipv4Addr; 2] = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+// Half-open connections a port keeps state for before using SYN cookies.
+const DEFAULT_SYN_BACKLOG: usize = 128;
+// Established connections a listener queues up for try_accept.
+pub(crate) const DEFAULT_BACKLOG: usize = 128;
+// How long packet_loop waits for a packet before running the TCP timers.
+const TICK: Duration = Duration::from_millis(10);
 
-#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
-struct Quad {
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) struct Quad {
     src: (Ipv4Addr, u16),
     dst: (Ipv4Addr, u16),
 }
 
-#[derive(Default)]
+impl Quad {
+    pub(crate) fn remote(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.src.0, self.src.1)
+    }
+
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub(crate) fn local(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.dst.0, self.dst.1)
+    }
+}
+
+/// Datagrams an interface threw away without handing them to a
+/// connection, and why.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Not an IPv4 datagram carrying a TCP header.
+    pub malformed: usize,
+    /// Shorter than their IP header says.
+    pub truncated: usize,
+    pub bad_checksum: usize,
+    /// Times writing to the capture failed, which stops it.
+    pub capture_errors: usize,
+}
+
 struct Handler {
     coordinator: Mutex<ConnectionCoordinator>,
-    pending_var: Condvar,
-    rcv_var: Condvar,
+    // Signalled whenever any stream or listener might have become ready,
+    // for Interface::poll.
+    poll: Signal,
+}
+
+/// Wakes up the threads blocked on an event as well as the tasks waiting for
+/// it.
+#[derive(Default)]
+pub(crate) struct Signal {
+    var: Condvar,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl Signal {
+    fn notify(&self) {
+        self.var.notify_all();
+        for waker in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Has the next `notify` wake `waker`, along with the other tasks
+    /// waiting. Must be called with the coordinator locked, so no
+    /// notification slips in between a task seeing it isn't ready and
+    /// registering.
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        match wakers.iter_mut().find(|w| w.will_wake(waker)) {
+            Some(current) => current.clone_from(waker),
+            None => wakers.push(waker.clone()),
+        }
+    }
+}
+
+/// What the threads blocked on one stream wait for. Each stream has its own
+/// so a segment only wakes up the stream it's for.
+#[derive(Default)]
+struct Wakeup {
+    rcv: Signal,
+    send: Signal,
 }
 
 type InterfaceHandle = Arc<Handler>;
@@ -51,267 +136,1882 @@ impl Drop for Interface {
     }
 }
 
-#[derive(Default)]
-struct ConnectionCoordinator {
+pub(crate) struct ConnectionCoordinator {
     terminate: bool,
-    connections: HashMap<Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>,
+    // Ordered so that timers fire in the same order every run.
+    connections: BTreeMap<Quad, tcp::Connection>,
+    listeners: HashMap<u16, Listener>,
+    syn_backlog: usize,
+    syn_cookies: syncookie::SynCookies,
+    fast_open: tfo::FastOpen,
+    isn: tcp::IsnGenerator,
+    next_port: u16,
+    registrations: HashMap<Socket, (Token, Ready)>,
+    // Wakeups of the connections a TcpStream refers to.
+    wakeups: HashMap<Quad, Arc<Wakeup>>,
+    // Segments waiting for the driver to send them.
+    outbox: tcp::Outbox,
+    // What new connections are set up with, the MSS the device's MTU
+    // allows among others.
+    config: tcp::Config,
+    // Whether the device leaves checking checksums to us.
+    verify_checksums: bool,
+    // Our address, datagrams for any other are ignored.
+    addr: Ipv4Addr,
+    // Time of the last tick or datagram, the clock as far as opening
+    // connections is concerned.
+    now: Instant,
+    // Where the traffic is recorded, if anywhere.
+    capture: Option<Capture>,
+    stats: Stats,
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, handler: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
-    loop {
-        //TODO: set a timeout for TCP timers and termiantion of coordinator
-        let n = nic.recv(&mut buf).expect("Failed to recv on nic");
-        // If no without_packet_info, those are required.
-        // let _flags = u16::from_be_bytes([buf[0], buf[1]]);
-        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-        // if eth_proto != IP_V4_PROTOCOL {
-        //     continue;
-        // }
+struct Listener {
+    // What try_accept waits on.
+    pending: Arc<Signal>,
+    backlog: usize,
+    nonblocking: bool,
+    // Connections still in the handshake, at most `syn_backlog` of them.
+    syn_queue: HashSet<Quad>,
+    // Connections ready to be handed out by `try_accept`, at most `backlog`.
+    accept_queue: VecDeque<Quad>,
+}
 
-        // TODO: if self.terminate && Arc::get_strong_count(&conn_cord) == 1
-        // tear down all connections
+impl Listener {
+    fn is_full(&self) -> bool {
+        self.accept_queue.len() >= self.backlog
+    }
+}
+
+impl Default for ConnectionCoordinator {
+    fn default() -> Self {
+        ConnectionCoordinator {
+            terminate: false,
+            connections: Default::default(),
+            listeners: Default::default(),
+            syn_backlog: DEFAULT_SYN_BACKLOG,
+            syn_cookies: Default::default(),
+            fast_open: Default::default(),
+            isn: Default::default(),
+            next_port: 0,
+            registrations: Default::default(),
+            wakeups: Default::default(),
+            outbox: Default::default(),
+            config: Default::default(),
+            verify_checksums: true,
+            addr: LOCAL_ADDR,
+            now: Instant::now(),
+            capture: None,
+            stats: Default::default(),
+        }
+    }
+}
+
+impl ConnectionCoordinator {
+    /// A coordinator for connections running over `device`, answering on
+    /// `addr`. The MSS in `config` is the one the device's MTU allows.
+    pub(crate) fn for_device(device: &impl NetDevice, addr: Ipv4Addr, config: tcp::Config) -> Self {
+        ConnectionCoordinator {
+            addr,
+            config: tcp::Config {
+                link_mss: tcp::mss_for_mtu(device.mtu()),
+                ..config
+            },
+            verify_checksums: !device.capabilities().contains(Capabilities::RX_CHECKSUM),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    /// Derives the secrets from `seed` instead of fresh randomness and starts
+    /// the clocks at `epoch`, so that a run can be repeated exactly.
+    pub(crate) fn seed(&mut self, seed: u64, epoch: Instant) {
+        self.isn = tcp::IsnGenerator::new(siphash::key_from_seed(seed, 0), epoch);
+        self.syn_cookies = syncookie::SynCookies::new(siphash::key_from_seed(seed, 1), epoch);
+        self.fast_open = tfo::FastOpen::new(siphash::key_from_seed(seed, 2));
+        self.now = epoch;
+    }
+
+    /// Handles a segment for a port we listen on that doesn't belong to any
+    /// connection yet. Returns whether a connection joined the accept queue.
+    ///
+    /// When the SYN queue is full new handshakes go through SYN cookies. When
+    /// the accept queue is full SYNs and the ACKs completing handshakes are
+    /// dropped, the peer retransmits them and we get another chance once the
+    /// application caught up.
+    fn on_listen(&mut self, quad: Quad, segment: &tcp::Segment, now: Instant) -> bool {
+        let listener = match self.listeners.get_mut(&quad.dst.1) {
+            Some(listener) => listener,
+            None => {
+                tcp::send_reset(&mut self.outbox, segment);
+                return false;
+            }
+        };
+        if listener.is_full() {
+            return false;
+        }
+        let tcp_header = &segment.tcp_header;
 
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..n]) {
-            Ok(ip_header) => {
-                let src = ip_header.source_addr();
-                let dst = ip_header.destination_addr();
-                let proto = ip_header.protocol().0;
-                if proto != TCP_PROTOCOL {
-                    continue;
+        if tcp_header.syn() && !tcp_header.ack() {
+            if listener.syn_queue.len() >= self.syn_backlog {
+                // Answer without keeping any state.
+                let iss = self.syn_cookies.generate(
+                    now,
+                    quad.dst,
+                    quad.src,
+                    tcp_header.sequence_number(),
+                    tcp::peer_mss(tcp_header),
+                );
+                let segment = tcp::Segment {
+                    payload: &[],
+                    ..segment.clone()
+                };
+                tcp::Connection::accept(&mut self.outbox, now, &segment, iss, &self.config, None);
+                return false;
+            }
+
+            let iss = self.isn.generate(now, quad.dst, quad.src);
+            let c = match tcp::Connection::accept(
+                &mut self.outbox,
+                now,
+                segment,
+                iss,
+                &self.config,
+                Some(&self.fast_open),
+            ) {
+                Some(c) => c,
+                None => return false,
+            };
+            // Data that came with a Fast Open cookie is for the application
+            // to see right away, no need to wait for the handshake.
+            let early = c.has_fast_open_data();
+            if early {
+                listener.accept_queue.push_back(quad);
+            } else {
+                listener.syn_queue.insert(quad);
+            }
+            self.connections.insert(quad, c);
+            return early;
+        }
+
+        if tcp_header.ack() && !tcp_header.rst() {
+            // Possibly completing a handshake we answered with a SYN cookie.
+            let iss = tcp_header.acknowledgment_number().wrapping_sub(1);
+            let irs = tcp_header.sequence_number().wrapping_sub(1);
+            if let Some(mss) = self.syn_cookies.check(now, quad.dst, quad.src, irs, iss) {
+                let mut c = tcp::Connection::from_syn_cookie(segment, iss, mss, &self.config);
+                c.on_packet(&mut self.outbox, now, segment);
+                if c.is_synchronized() {
+                    listener.accept_queue.push_back(quad);
+                    self.connections.insert(quad, c);
+                    return true;
                 }
+            }
+            // RFC 9293, section 3.10.7.2: an ACK in LISTEN is bad whatever
+            // it acknowledges.
+            tcp::send_reset(&mut self.outbox, segment);
+        }
+        false
+    }
 
-                let ip_header_size = ip_header.slice().len();
-                match etherparse::TcpHeaderSlice::from_slice(&buf[ip_header_size..n]) {
-                    Ok(tcp_header) => {
-                        let tcp_header_size = tcp_header.slice().len();
-                        let data_pos = ip_header_size + tcp_header_size;
-                        let mut conn_cord_guard = handler.coordinator.lock().unwrap();
-                        let conn_cord = &mut *conn_cord_guard;
-                        let src_port = tcp_header.source_port();
-                        let dst_port = tcp_header.destination_port();
-                        let quad = Quad {
-                            src: (src, src_port),
-                            dst: (dst, dst_port),
-                        };
-                        match conn_cord.connections.entry(quad) {
-                            Entry::Occupied(mut c) => {
-                                let conn_availability = c
-                                    .get_mut()
-                                    .on_packet(&mut nic, ip_header, tcp_header, &buf[data_pos..n])
-                                    .expect("Failed to handle packet");
-                                drop(conn_cord_guard);
-                                if conn_availability.contains(Available::READ) {
-                                    eprintln!("Now available for reading");
-                                    handler.rcv_var.notify_all();
-                                }
-                                if conn_availability.contains(Available::WRITE) {
-                                    eprintln!("now asjdhasjda");
-                                    // handler.send_var.notify_all();
-                                }
-                            }
-                            Entry::Vacant(e) => {
-                                if let Some(pending) =
-                                    conn_cord.pending.get_mut(&tcp_header.destination_port())
-                                {
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut nic,
-                                        ip_header,
-                                        tcp_header,
-                                        &buf[data_pos..n],
-                                    )
-                                    .expect("Failed to accept connection")
-                                    {
-                                        println!("Accepted a new connection, inserting it to the connections map.");
-                                        e.insert(c);
-                                        pending.push_back(quad);
-                                        drop(conn_cord_guard);
-                                        handler.pending_var.notify_all();
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("ignoring weird tcp packet {:?}", e);
-                    }
+    /// Whether `quad` is waiting for the ACK completing its handshake while
+    /// there's no room to queue it.
+    fn is_handshake_blocked(&self, quad: &Quad) -> bool {
+        self.listeners
+            .get(&quad.dst.1)
+            .is_some_and(|l| l.is_full() && l.syn_queue.contains(quad))
+    }
+
+    /// Moves `quad` along once its handshake is over: to the accept queue if
+    /// it got established, out of the way if it failed. Returns whether the
+    /// accept queue grew.
+    fn on_handshake_progress(&mut self, quad: &Quad) -> bool {
+        let listener = match self.listeners.get_mut(&quad.dst.1) {
+            Some(listener) if listener.syn_queue.contains(quad) => listener,
+            _ => return false,
+        };
+        match self.connections.get(quad) {
+            Some(c) if c.is_half_open() => false,
+            Some(c) if c.is_synchronized() => {
+                listener.syn_queue.remove(quad);
+                listener.accept_queue.push_back(*quad);
+                true
+            }
+            _ => {
+                // Reset or timed out before anyone got to see it.
+                listener.syn_queue.remove(quad);
+                self.connections.remove(quad);
+                false
+            }
+        }
+    }
+
+    /// Gives a new TcpStream for `quad` its own wakeup.
+    fn attach(&mut self, quad: Quad) -> Arc<Wakeup> {
+        self.wakeups.entry(quad).or_default().clone()
+    }
+
+    /// Wakeup of the listener on `port`, if it's still there.
+    fn pending(&self, port: u16) -> Option<Arc<Signal>> {
+        self.listeners.get(&port).map(|l| l.pending.clone())
+    }
+
+    pub(crate) fn stream(&mut self, quad: &Quad) -> io::Result<&mut tcp::Connection> {
+        self.connections.get_mut(quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Connection not found, despite TcpStream being preset.",
+            )
+        })
+    }
+
+    /// Readiness of `socket`, as reported by `Interface::poll`.
+    pub(crate) fn readiness(&self, socket: Socket) -> Ready {
+        match socket.0 {
+            SocketKind::Stream(quad) => {
+                let conn = match self.connections.get(&quad) {
+                    Some(conn) => conn,
+                    None => return Ready::HUP,
+                };
+                let mut ready = Ready::empty();
+                let available = conn.availability();
+                if available.contains(Available::READ) {
+                    ready |= Ready::READABLE;
+                }
+                if available.contains(Available::WRITE) {
+                    ready |= Ready::WRITABLE;
+                }
+                if conn.is_rcv_closed() {
+                    ready |= Ready::HUP;
                 }
+                if conn.is_reset() {
+                    ready |= Ready::ERROR;
+                }
+                ready
             }
-            Err(e) => {
-                eprintln!("ignoring weird ip packet {:?}", e);
+            SocketKind::Listener(port) => match self.listeners.get(&port) {
+                Some(l) if !l.accept_queue.is_empty() => Ready::READABLE,
+                _ => Ready::empty(),
+            },
+        }
+    }
+
+    fn ephemeral_port(&mut self, remote: (Ipv4Addr, u16)) -> Option<u16> {
+        let span = EPHEMERAL_PORTS.len() as u16;
+        for _ in 0..span {
+            let port = EPHEMERAL_PORTS.start() + self.next_port % span;
+            self.next_port = self.next_port.wrapping_add(1);
+            let quad = Quad {
+                src: remote,
+                dst: (self.addr, port),
+            };
+            if !self.listeners.contains_key(&port) && !self.connections.contains_key(&quad) {
+                return Some(port);
             }
         }
+        None
     }
 }
 
-impl Default for Interface {
-    fn default() -> Self {
-        let nic = tun_tap::Iface::without_packet_info("tun0", Mode::Tun)
-            .expect("Failed to create tun interface");
-        let handler = Arc::new(Handler::default());
-        let handle = {
-            let handler = handler.clone();
-            std::thread::spawn(move || {
-                // Do the main accept loop.
-                packet_loop(nic, handler)
-            })
-        };
-        Interface {
-            handler: Some(handler),
-            t_handle: Some(handle),
+/// Threads and tasks to wake up once the coordinator is unlocked again.
+#[derive(Default)]
+pub(crate) struct Notifications {
+    readable: Vec<Arc<Wakeup>>,
+    writable: Vec<Arc<Wakeup>>,
+    accepted: Vec<Arc<Signal>>,
+}
+
+impl Notifications {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.readable.is_empty() && self.writable.is_empty() && self.accepted.is_empty()
+    }
+
+    fn notify(self, poll: &Signal) {
+        for wakeup in &self.readable {
+            wakeup.rcv.notify();
+        }
+        for wakeup in &self.writable {
+            wakeup.send.notify();
+        }
+        for pending in &self.accepted {
+            pending.notify();
+        }
+        if !self.is_empty() {
+            poll.notify();
         }
     }
 }
 
-impl Interface {
-    pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
-        let mut conn_cord = self.handler.as_mut().unwrap().coordinator.lock().unwrap();
-        // TODO - accept SYN packets on given port.
-        match conn_cord.pending.entry(port) {
-            Entry::Occupied(_) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::AddrInUse,
-                    "Port already bound",
-                ));
+impl ConnectionCoordinator {
+    /// Runs the timers of every connection and drops the ones that are done.
+    pub(crate) fn on_tick(&mut self, now: Instant) -> Notifications {
+        self.now = now;
+        let mut notifications = Notifications::default();
+        let mut handshakes = Vec::new();
+        for (quad, conn) in self.connections.iter_mut() {
+            let synchronized = conn.is_synchronized();
+            let half_open = conn.is_half_open();
+            conn.on_tick(&mut self.outbox, now);
+            if synchronized != conn.is_synchronized() || conn.is_reset() {
+                if let Some(wakeup) = self.wakeups.get(quad) {
+                    notifications.readable.push(wakeup.clone());
+                    notifications.writable.push(wakeup.clone());
+                }
             }
-            Entry::Vacant(p) => {
-                p.insert(Default::default());
+            if half_open && !conn.is_half_open() {
+                handshakes.push(*quad);
             }
-        };
-        drop(conn_cord);
-        Ok(TcpListener(port, self.handler.as_mut().unwrap().clone()))
+        }
+        for quad in &handshakes {
+            if self.on_handshake_progress(quad) {
+                notifications.accepted.extend(self.pending(quad.dst.1));
+            }
+        }
+        self.connections
+            .retain(|_, c| !(c.is_abandoned() && c.is_closed()));
+        notifications
     }
-}
 
-pub struct TcpStream(Quad, InterfaceHandle);
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub(crate) fn connections(&self) -> impl Iterator<Item = (&Quad, &tcp::Connection)> {
+        self.connections.iter()
+    }
 
-impl Drop for TcpStream {
-    fn drop(&mut self) {
-        eprintln!("Dropping TcpStream");
-        //TODO: Eventually remove from active connections
+    /// When [`ConnectionCoordinator::on_tick`] next has work to do, `now` if
+    /// right away.
+    pub(crate) fn poll_at(&self, now: Instant) -> Option<Instant> {
+        self.connections
+            .values()
+            .filter_map(|c| c.poll_at(now))
+            .min()
     }
-}
 
-impl Read for TcpStream {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut conn_cord = self.1.coordinator.lock().unwrap();
-        loop {
-            let conn = conn_cord.connections.get_mut(&self.0).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Connection not found, despite TcpStream being preset.",
-                )
-            })?;
+    /// Handles an IP datagram read from the device at `now`.
+    pub(crate) fn on_datagram(&mut self, datagram: &[u8], now: Instant) -> Notifications {
+        self.now = now;
+        self.record(datagram, true);
+        let mut notifications = Notifications::default();
+        // If no without_packet_info, those are required.
+        // let _flags = u16::from_be_bytes([buf[0], buf[1]]);
+        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
+        // if eth_proto != IP_V4_PROTOCOL {
+        //     continue;
+        // }
+
+        let ip_header = match etherparse::Ipv4HeaderSlice::from_slice(datagram) {
+            Ok(ip_header) => ip_header,
+            Err(_) => {
+                self.stats.malformed += 1;
+                return notifications;
+            }
+        };
+        let src = ip_header.source_addr();
+        let dst = ip_header.destination_addr();
+        let proto = ip_header.protocol().0;
+        if proto != TCP_PROTOCOL || dst != self.addr {
+            return notifications;
+        }
 
-            if conn.is_rcv_closed() && conn.incomming.is_empty() {
-                return Ok(0);
+        let ip_header_size = ip_header.slice().len();
+        let total_len = ip_header.total_len() as usize;
+        if total_len < ip_header_size || total_len > datagram.len() {
+            self.stats.truncated += 1;
+            return notifications;
+        }
+        // Anything past the total length is link layer padding.
+        let datagram = &datagram[..total_len];
+        let tcp_header = match etherparse::TcpHeaderSlice::from_slice(&datagram[ip_header_size..]) {
+            Ok(tcp_header) => tcp_header,
+            Err(_) => {
+                self.stats.malformed += 1;
+                return notifications;
             }
-            if !conn.incomming.is_empty() {
-                //TODO: return FIN if nread == 0
-                let mut nread = 0;
-                let (head, tail) = conn.incomming.as_slices();
-                let hread = std::cmp::min(buf.len(), head.len());
-                buf.copy_from_slice(&head[..hread]);
-                let tread = std::cmp::min(buf.len() - hread, tail.len());
-                buf.copy_from_slice(&tail[..tread]);
-                nread += tread;
-                drop(conn.incomming.drain(..nread));
-                return Ok(nread);
+        };
+        let tcp_header_size = tcp_header.slice().len();
+        let src_port = tcp_header.source_port();
+        let dst_port = tcp_header.destination_port();
+        let quad = Quad {
+            src: (src, src_port),
+            dst: (dst, dst_port),
+        };
+        let segment = tcp::Segment {
+            ip_header,
+            tcp_header,
+            payload: &datagram[ip_header_size + tcp_header_size..],
+        };
+        if self.verify_checksums && !segment.is_intact() {
+            self.stats.bad_checksum += 1;
+            return notifications;
+        }
+        let tcp_header = &segment.tcp_header;
+        if tcp_header.ack() && !tcp_header.rst() && self.is_handshake_blocked(&quad) {
+            return notifications;
+        }
+        let c = match self.connections.get_mut(&quad) {
+            Some(c) => c,
+            None => {
+                if self.on_listen(quad, &segment, now) {
+                    notifications.accepted.extend(self.pending(dst_port));
+                }
+                return notifications;
             }
+        };
 
-            conn_cord = self.1.rcv_var.wait(conn_cord).unwrap();
+        let synchronized = c.is_synchronized();
+        let write_done = c.is_write_done();
+        let unacked = c.unacked.len();
+        let conn_availability = c.on_packet(&mut self.outbox, now, &segment);
+        let state_changed =
+            synchronized != c.is_synchronized() || c.is_reset() || write_done != c.is_write_done();
+        let acked = c.unacked.len() < unacked;
+        match c.take_fast_open_cookie() {
+            Some(cookie) if cookie.is_empty() => self.fast_open.forget_cookie(src),
+            Some(cookie) => self.fast_open.cache_cookie(src, cookie),
+            None => {}
+        }
+        if self.on_handshake_progress(&quad) {
+            notifications.accepted.extend(self.pending(dst_port));
+        }
+        if let Some(wakeup) = self.wakeups.get(&quad) {
+            if conn_availability.contains(Available::READ) || state_changed {
+                notifications.readable.push(wakeup.clone());
+            }
+            if acked || state_changed {
+                notifications.writable.push(wakeup.clone());
+            }
         }
+        notifications
     }
-}
 
-impl Write for TcpStream {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut conn_cord = self.1.coordinator.lock().unwrap();
-        let conn = conn_cord.connections.get_mut(&self.0).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Connection not found, despite TcpStream being preset.",
-            )
-        })?;
+    pub(crate) fn listen(&mut self, port: u16, backlog: usize) -> io::Result<Arc<Signal>> {
+        match self.listeners.entry(port) {
+            Entry::Occupied(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "Port already bound",
+            )),
+            Entry::Vacant(p) => {
+                let listener = p.insert(Listener {
+                    pending: Default::default(),
+                    backlog: backlog.max(1),
+                    nonblocking: false,
+                    syn_queue: Default::default(),
+                    accept_queue: Default::default(),
+                });
+                Ok(listener.pending.clone())
+            }
+        }
+    }
 
-        if conn.unacked.len() >= SEND_QUEUE_SIZE {
-            // TODO: Block
-            return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "Too many bytes buffered",
-            ));
+    /// Frees `port` and resets every connection nobody accepted yet.
+    pub(crate) fn unlisten(&mut self, port: u16) {
+        self.registrations
+            .remove(&Socket(SocketKind::Listener(port)));
+        let listener = match self.listeners.remove(&port) {
+            Some(listener) => listener,
+            None => return,
+        };
+        for quad in listener.syn_queue.iter().chain(&listener.accept_queue) {
+            if let Some(c) = self.connections.get_mut(quad) {
+                // on_tick sends the RST and removes it.
+                c.abort();
+                c.abandon();
+            }
         }
+    }
 
-        let nwrite = std::cmp::min(buf.len(), SEND_QUEUE_SIZE - conn.unacked.len());
-        conn.unacked.extend(&buf[..nwrite]);
+    /// Next established connection waiting on `port`.
+    pub(crate) fn accept(&mut self, port: u16) -> io::Result<Option<Quad>> {
+        Ok(self.listener(port)?.accept_queue.pop_front())
+    }
 
-        //TODO: wake up a writer
-        Ok(nwrite)
+    fn listener(&mut self, port: u16) -> io::Result<&mut Listener> {
+        self.listeners
+            .get_mut(&port)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Port not bound"))
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        let mut conn_cord = self.1.coordinator.lock().unwrap();
-        let conn = conn_cord.connections.get_mut(&self.0).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Connection not found, despite TcpStream being preset.",
-            )
+    /// Starts a connection to `addr`, the SYN goes out on the next tick.
+    pub(crate) fn open(
+        &mut self,
+        addr: SocketAddrV4,
+        fast_open: Option<&[u8]>,
+        data: &[u8],
+    ) -> io::Result<Quad> {
+        let remote = (*addr.ip(), addr.port());
+        let port = self.ephemeral_port(remote).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "No ephemeral port left")
         })?;
+        let quad = Quad {
+            src: remote,
+            dst: (self.addr, port),
+        };
+        let iss = self.isn.generate(self.now, quad.dst, quad.src);
+        self.connections.insert(
+            quad,
+            tcp::Connection::connect(quad.dst, quad.src, iss, &self.config, fast_open, data),
+        );
+        Ok(quad)
+    }
 
-        if conn.unacked.is_empty() {
-            Ok(())
-        } else {
-            // TODO: Block
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "Too many bytes buffered",
-            ))
+    /// The application let go of `quad`: it's closed in the background and
+    /// removed once that's done.
+    pub(crate) fn release(&mut self, quad: Quad) {
+        self.registrations.remove(&Socket(SocketKind::Stream(quad)));
+        self.wakeups.remove(&quad);
+        if let btree_map::Entry::Occupied(mut c) = self.connections.entry(quad) {
+            if c.get().is_closed() {
+                c.remove();
+            } else {
+                // on_tick removes it once the close handshake is done.
+                c.get_mut().abandon();
+            }
+        }
+    }
+
+    /// Hands over the segments queued for sending since the last call, for
+    /// the driver to put on the wire.
+    pub(crate) fn take_outbox(&mut self) -> tcp::Outbox {
+        let outbox = std::mem::take(&mut self.outbox);
+        for datagram in &outbox {
+            self.record(datagram, false);
+        }
+        outbox
+    }
+
+    pub(crate) fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    pub(crate) fn set_syn_backlog(&mut self, backlog: usize) {
+        self.syn_backlog = backlog;
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub(crate) fn fast_open(&self) -> &tfo::FastOpen {
+        &self.fast_open
+    }
+
+    pub(crate) fn fast_open_mut(&mut self) -> &mut tfo::FastOpen {
+        &mut self.fast_open
+    }
+
+    fn record(&mut self, datagram: &[u8], incoming: bool) {
+        let Some(capture) = &mut self.capture else {
+            return;
+        };
+        if capture.record(datagram, incoming, self.now).is_err() {
+            // Not worth taking the connections down for.
+            self.stats.capture_errors += 1;
+            self.capture = None;
         }
     }
 }
 
-pub struct TcpListener(u16, InterfaceHandle);
+/// Sends what the connections queued up, in order.
+pub(crate) fn transmit(device: &mut impl NetDevice, outbox: tcp::Outbox) -> io::Result<()> {
+    for datagram in outbox {
+        device.send(&datagram)?;
+    }
+    Ok(())
+}
 
-impl TcpListener {
-    pub fn try_accept(&self) -> std::io::Result<TcpStream> {
-        let mut conn_cord = self.1.coordinator.lock().unwrap();
-        loop {
-            if let Some(quad) = conn_cord
-                .pending
-                .get_mut(&self.0)
-                .expect("Port closed while connection is still alive...")
-                .pop_front()
-            {
-                return Ok(TcpStream(quad, self.1.clone()));
-            } else {
-                // TODO: Block
-                conn_cord = self.1.pending_var.wait(conn_cord).unwrap();
+fn packet_loop(mut device: impl NetDevice, handler: InterfaceHandle) -> io::Result<()> {
+    let mut buf = vec![0u8; device.mtu()];
+    loop {
+        let received = device.recv(&mut buf, TICK)?;
+
+        let mut conn_cord = handler.coordinator.lock().unwrap();
+        if conn_cord.terminate && Arc::strong_count(&handler) == 1 {
+            // Nothing drives the connections still closing in the background
+            // from now on, reset them rather than leave the peers hanging.
+            for c in conn_cord.connections.values_mut() {
+                c.abort();
             }
+            conn_cord.on_tick(Instant::now());
+            let outbox = conn_cord.take_outbox();
+            drop(conn_cord);
+            return transmit(&mut device, outbox);
         }
+        let notifications = conn_cord.on_tick(Instant::now());
+        let outbox = conn_cord.take_outbox();
+        drop(conn_cord);
+        transmit(&mut device, outbox)?;
+        notifications.notify(&handler.poll);
+        let n = match received {
+            Some(n) => n,
+            None => continue,
+        };
+
+        let mut conn_cord = handler.coordinator.lock().unwrap();
+        let notifications = conn_cord.on_datagram(&buf[..n], Instant::now());
+        let outbox = conn_cord.take_outbox();
+        drop(conn_cord);
+        transmit(&mut device, outbox)?;
+        notifications.notify(&handler.poll);
     }
 }
 
-impl Drop for TcpListener {
-    fn drop(&mut self) {
-        let pending = self
-            .1
+impl Default for Interface {
+    /// An interface on `tun0` with the [`InterfaceBuilder`] defaults.
+    fn default() -> Self {
+        InterfaceBuilder::new()
+            .build()
+            .expect("Failed to set up the tun interface")
+    }
+}
+
+impl Interface {
+    /// Runs the stack over `device`, on a thread of its own.
+    pub fn new(device: impl NetDevice) -> Self {
+        Interface::with_addr(device, LOCAL_ADDR)
+    }
+
+    /// Like [`Interface::new`], answering on `addr`.
+    pub fn with_addr(device: impl NetDevice, addr: Ipv4Addr) -> Self {
+        Interface::with_config(device, addr, Default::default())
+    }
+
+    /// Sets up a tun device and an interface on top of it, see
+    /// [`InterfaceBuilder`].
+    pub fn builder() -> InterfaceBuilder {
+        InterfaceBuilder::new()
+    }
+
+    pub(crate) fn with_config(device: impl NetDevice, addr: Ipv4Addr, config: tcp::Config) -> Self {
+        let handler = Arc::new(Handler {
+            coordinator: Mutex::new(ConnectionCoordinator::for_device(&device, addr, config)),
+            poll: Default::default(),
+        });
+        let handle = {
+            let handler = handler.clone();
+            std::thread::spawn(move || {
+                // Do the main accept loop.
+                packet_loop(device, handler)
+            })
+        };
+        Interface {
+            handler: Some(handler),
+            t_handle: Some(handle),
+        }
+    }
+
+    /// Two interfaces wired back to back through a [`MemoryDevice`], at
+    /// 10.0.0.1 and 10.0.0.2. Needs neither root nor a tun device.
+    pub fn loopback_pair() -> (Interface, Interface) {
+        let (a, b) = MemoryDevice::pair();
+        (
+            Interface::with_addr(a, LOOPBACK_ADDRS[0]),
+            Interface::with_addr(b, LOOPBACK_ADDRS[1]),
+        )
+    }
+
+    /// The address the interface answers on.
+    pub fn addr(&self) -> Ipv4Addr {
+        self.handler
+            .as_ref()
+            .unwrap()
             .coordinator
             .lock()
             .unwrap()
-            .pending
-            .remove(&self.0)
-            .expect("");
-        for quad in pending {
-            //TODO: terminate conn_cord.connections[quad];
-            unimplemented!()
+            .addr
+    }
+
+    pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
+        self.bind_with_backlog(port, DEFAULT_BACKLOG)
+    }
+
+    /// Like [`Interface::bind`], with room for `backlog` established
+    /// connections (at least one) waiting to be accepted.
+    pub fn bind_with_backlog(&mut self, port: u16, backlog: usize) -> io::Result<TcpListener> {
+        let handler = self.handler.as_mut().unwrap();
+        let pending = handler.coordinator.lock().unwrap().listen(port, backlog)?;
+        Ok(TcpListener(
+            port,
+            self.handler.as_mut().unwrap().clone(),
+            pending,
+        ))
+    }
+
+    /// Opens a connection to `addr`, blocking until the handshake completes.
+    pub fn connect(&mut self, addr: SocketAddrV4) -> io::Result<TcpStream> {
+        let stream = self.open(addr, None, &[])?;
+        let mut conn_cord = stream.1.coordinator.lock().unwrap();
+        loop {
+            if let Some(result) = connect_now(conn_cord.stream(&stream.0)?) {
+                drop(conn_cord);
+                return result.map(|()| stream);
+            }
+            conn_cord = wait_until(&stream.2.rcv, conn_cord, None);
         }
     }
-}
 
-impl TcpStream {
-    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
-        //TODO: terminate connection gracefully with FIN
-        Ok(())
+    /// Sends the SYN for a connection to `addr` without waiting for the
+    /// answer, see [`TcpStream::poll_connect`].
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub(crate) fn start_connect(&mut self, addr: SocketAddrV4) -> io::Result<TcpStream> {
+        self.open(addr, None, &[])
+    }
+
+    /// Opens a connection to `addr` using TCP Fast Open, returning without
+    /// waiting for the handshake.
+    ///
+    /// If we hold a cookie for the server, `data` is carried on the SYN and
+    /// reaches the server one round trip earlier. Otherwise the SYN asks for a
+    /// cookie for next time and `data` is sent once the connection is
+    /// established.
+    pub fn connect_fast_open(&mut self, addr: SocketAddrV4, data: &[u8]) -> io::Result<TcpStream> {
+        let conn_cord = self.handler.as_ref().unwrap().coordinator.lock().unwrap();
+        if data.len() > conn_cord.config.send_buffer_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too many bytes for the send queue",
+            ));
+        }
+        let cookie = conn_cord
+            .fast_open
+            .cached_cookie(*addr.ip())
+            .map_or_else(Vec::new, <[u8]>::to_vec);
+        drop(conn_cord);
+        self.open(addr, Some(&cookie), data)
+    }
+
+    fn open(
+        &mut self,
+        addr: SocketAddrV4,
+        fast_open: Option<&[u8]>,
+        data: &[u8],
+    ) -> io::Result<TcpStream> {
+        let handler = self.handler.as_ref().unwrap().clone();
+        let mut conn_cord = handler.coordinator.lock().unwrap();
+        let quad = conn_cord.open(addr, fast_open, data)?;
+        let wakeup = conn_cord.attach(quad);
+        drop(conn_cord);
+        Ok(TcpStream(quad, handler, wakeup))
+    }
+
+    /// Sets how many connections in the middle of a handshake each listener
+    /// keeps state for. SYNs beyond that are answered with SYN cookies.
+    pub fn set_syn_backlog(&mut self, backlog: usize) {
+        self.handler
+            .as_ref()
+            .unwrap()
+            .coordinator
+            .lock()
+            .unwrap()
+            .syn_backlog = backlog;
+    }
+
+    /// Lets listeners on this interface issue Fast Open cookies and accept
+    /// data carried on SYNs. Off by default, as data on a SYN may be replayed.
+    pub fn set_fast_open(&mut self, enabled: bool) {
+        self.handler
+            .as_ref()
+            .unwrap()
+            .coordinator
+            .lock()
+            .unwrap()
+            .fast_open
+            .enabled = enabled;
+    }
+
+    /// Replaces the secret Fast Open cookies are derived from. Cookies issued
+    /// with the previous key stay valid until the next rotation.
+    pub fn rotate_fast_open_key(&mut self) {
+        self.set_fast_open_key(siphash::random_key());
+    }
+
+    /// Like [`Interface::rotate_fast_open_key`], with a caller supplied key,
+    /// e.g. to share cookies between several interfaces.
+    pub fn set_fast_open_key(&mut self, key: [u8; 16]) {
+        self.handler
+            .as_ref()
+            .unwrap()
+            .coordinator
+            .lock()
+            .unwrap()
+            .fast_open
+            .rotate_key(key);
+    }
+
+    /// Records every datagram received and sent from now on into
+    /// `capture`, or stops recording with `None`.
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.handler
+            .as_ref()
+            .unwrap()
+            .coordinator
+            .lock()
+            .unwrap()
+            .set_capture(capture);
+    }
+
+    /// What the interface threw away so far.
+    pub fn stats(&self) -> Stats {
+        self.handler
+            .as_ref()
+            .unwrap()
+            .coordinator
+            .lock()
+            .unwrap()
+            .stats()
+    }
+
+    /// Starts watching `source` for the readiness in `interest`, reporting it
+    /// under `token`.
+    pub fn register(
+        &mut self,
+        source: &impl Source,
+        token: Token,
+        interest: Ready,
+    ) -> io::Result<()> {
+        let mut conn_cord = self.handler.as_ref().unwrap().coordinator.lock().unwrap();
+        match conn_cord.registrations.entry(source.socket()) {
+            Entry::Occupied(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Source already registered",
+            )),
+            Entry::Vacant(r) => {
+                r.insert((token, interest));
+                Ok(())
+            }
+        }
+    }
+
+    /// Changes the token and interest of a registered `source`.
+    pub fn reregister(
+        &mut self,
+        source: &impl Source,
+        token: Token,
+        interest: Ready,
+    ) -> io::Result<()> {
+        let mut conn_cord = self.handler.as_ref().unwrap().coordinator.lock().unwrap();
+        match conn_cord.registrations.get_mut(&source.socket()) {
+            Some(r) => {
+                *r = (token, interest);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Source not registered",
+            )),
+        }
+    }
+
+    /// Stops watching `source`. Dropping a stream or listener does this too.
+    pub fn deregister(&mut self, source: &impl Source) -> io::Result<()> {
+        let mut conn_cord = self.handler.as_ref().unwrap().coordinator.lock().unwrap();
+        match conn_cord.registrations.remove(&source.socket()) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Source not registered",
+            )),
+        }
+    }
+
+    /// Waits until at least one registered source is ready, or `timeout`
+    /// passed, and fills `events` with every ready source.
+    pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let handler = self.handler.as_ref().unwrap();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut conn_cord = handler.coordinator.lock().unwrap();
+        loop {
+            events.clear();
+            for (&socket, &(token, interest)) in &conn_cord.registrations {
+                let ready = conn_cord.readiness(socket) & (interest | Ready::HUP | Ready::ERROR);
+                if !ready.is_empty() {
+                    events.push(Event::new(token, ready));
+                }
+            }
+            if !events.is_empty() || deadline.is_some_and(|d| Instant::now() >= d) {
+                return Ok(());
+            }
+            conn_cord = wait_until(&handler.poll, conn_cord, deadline);
+        }
+    }
+}
+
+pub struct TcpStream(Quad, InterfaceHandle, Arc<Wakeup>);
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        let linger = match conn_cord.connections.get_mut(&self.0) {
+            Some(c) if c.options.linger == Some(Duration::ZERO) => {
+                c.abort();
+                None
+            }
+            Some(c) => {
+                c.close();
+                c.options.linger
+            }
+            None => None,
+        };
+        if let Some(linger) = linger {
+            // Wait for the peer to acknowledge our data and FIN, anything
+            // left after that is delivered in the background.
+            let deadline = Instant::now() + linger;
+            while let Some(c) = conn_cord.connections.get(&self.0) {
+                if c.is_write_done() || c.is_reset() || Instant::now() >= deadline {
+                    break;
+                }
+                conn_cord = wait_until(&self.2.send, conn_cord, Some(deadline));
+            }
+        }
+        conn_cord.release(self.0);
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        let deadline = conn_cord
+            .connections
+            .get(&self.0)
+            .and_then(|c| c.options.read_timeout)
+            .map(|timeout| Instant::now() + timeout);
+        loop {
+            let conn = conn_cord.stream(&self.0)?;
+            if let Some(result) = read_now(conn, buf) {
+                return result;
+            }
+            if conn.options.nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "No data available",
+                ));
+            }
+
+            check_deadline(deadline)?;
+            conn_cord = wait_until(&self.2.rcv, conn_cord, deadline);
+        }
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        let deadline = conn_cord
+            .connections
+            .get(&self.0)
+            .and_then(|c| c.options.write_timeout)
+            .map(|timeout| Instant::now() + timeout);
+        loop {
+            let conn = conn_cord.stream(&self.0)?;
+            if let Some(result) = write_now(conn, buf) {
+                return result;
+            }
+            if conn.options.nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Too many bytes buffered",
+                ));
+            }
+
+            check_deadline(deadline)?;
+            conn_cord = wait_until(&self.2.send, conn_cord, deadline);
+        }
+    }
+
+    /// Blocks until the peer acknowledged everything written so far.
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        let deadline = conn_cord
+            .connections
+            .get(&self.0)
+            .and_then(|c| c.options.write_timeout)
+            .map(|timeout| Instant::now() + timeout);
+        loop {
+            let conn = conn_cord.stream(&self.0)?;
+            if let Some(result) = flush_now(conn) {
+                return result;
+            }
+            if conn.options.nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Data not acknowledged yet",
+                ));
+            }
+
+            check_deadline(deadline)?;
+            conn_cord = wait_until(&self.2.send, conn_cord, deadline);
+        }
+    }
+}
+
+// The non-blocking halves of the stream operations, shared by the blocking
+// std::io implementations and the poll_* methods async wrappers build on.
+// `None` means the caller has to wait.
+
+pub(crate) fn connect_now(conn: &mut tcp::Connection) -> Option<io::Result<()>> {
+    if conn.is_reset() {
+        return Some(Err(reset_error(
+            conn,
+            io::ErrorKind::ConnectionRefused,
+            "Connection refused",
+        )));
+    }
+    conn.is_synchronized().then_some(Ok(()))
+}
+
+pub(crate) fn read_now(conn: &mut tcp::Connection, buf: &mut [u8]) -> Option<io::Result<usize>> {
+    if conn.is_read_closed() {
+        return Some(Ok(0));
+    }
+    if !conn.incomming.is_empty() {
+        let mut nread = 0;
+        let (head, tail) = conn.incomming.as_slices();
+        let hread = std::cmp::min(buf.len(), head.len());
+        buf[..hread].copy_from_slice(&head[..hread]);
+        nread += hread;
+        let tread = std::cmp::min(buf.len() - nread, tail.len());
+        buf[nread..nread + tread].copy_from_slice(&tail[..tread]);
+        nread += tread;
+        drop(conn.incomming.drain(..nread));
+        return Some(Ok(nread));
+    }
+    if conn.is_reset() {
+        return Some(Err(reset_error(
+            conn,
+            io::ErrorKind::ConnectionReset,
+            "Connection reset by peer",
+        )));
+    }
+    if conn.is_rcv_closed() {
+        return Some(Ok(0));
+    }
+    None
+}
+
+pub(crate) fn write_now(conn: &mut tcp::Connection, buf: &[u8]) -> Option<io::Result<usize>> {
+    if conn.is_write_closed() {
+        return Some(Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "Connection shut down for writing",
+        )));
+    }
+    if conn.is_reset() {
+        return Some(Err(reset_error(
+            conn,
+            io::ErrorKind::ConnectionReset,
+            "Connection reset by peer",
+        )));
+    }
+    if conn.unacked.len() < conn.send_buffer_size {
+        let nwrite = std::cmp::min(buf.len(), conn.send_buffer_size - conn.unacked.len());
+        conn.unacked.extend(&buf[..nwrite]);
+        return Some(Ok(nwrite));
+    }
+    None
+}
+
+pub(crate) fn flush_now(conn: &mut tcp::Connection) -> Option<io::Result<()>> {
+    if conn.unacked.is_empty() {
+        return Some(Ok(()));
+    }
+    if conn.is_reset() {
+        return Some(Err(reset_error(
+            conn,
+            io::ErrorKind::ConnectionReset,
+            "Connection reset by peer",
+        )));
+    }
+    None
+}
+
+// Why a reset connection failed: `kind` if the peer reset it, `TimedOut` if
+// we gave up on it.
+fn reset_error(conn: &tcp::Connection, kind: io::ErrorKind, msg: &str) -> io::Error {
+    if conn.is_timed_out() {
+        io::Error::new(io::ErrorKind::TimedOut, "Connection timed out")
+    } else {
+        io::Error::new(kind, msg)
+    }
+}
+
+/// Blocks until `signal` is notified, but no longer than until `deadline`.
+fn wait_until<'a>(
+    signal: &Signal,
+    conn_cord: MutexGuard<'a, ConnectionCoordinator>,
+    deadline: Option<Instant>,
+) -> MutexGuard<'a, ConnectionCoordinator> {
+    match deadline {
+        Some(deadline) => {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            signal.var.wait_timeout(conn_cord, timeout).unwrap().0
+        }
+        None => signal.var.wait(conn_cord).unwrap(),
+    }
+}
+
+fn check_deadline(deadline: Option<Instant>) -> io::Result<()> {
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Operation timed out",
+        ));
+    }
+    Ok(())
+}
+
+pub struct TcpListener(u16, InterfaceHandle, Arc<Signal>);
+
+impl TcpListener {
+    pub(crate) fn port(&self) -> u16 {
+        self.0
+    }
+
+    pub fn try_accept(&self) -> std::io::Result<TcpStream> {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        loop {
+            if let Some(quad) = conn_cord.accept(self.0)? {
+                let wakeup = conn_cord.attach(quad);
+                return Ok(TcpStream(quad, self.1.clone(), wakeup));
+            }
+            if conn_cord.listener(self.0)?.nonblocking {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "No connection to accept",
+                ));
+            }
+            conn_cord = wait_until(&self.2, conn_cord, None);
+        }
+    }
+
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub(crate) fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<TcpStream>> {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        match conn_cord.accept(self.0) {
+            Ok(Some(quad)) => {
+                let wakeup = conn_cord.attach(quad);
+                Poll::Ready(Ok(TcpStream(quad, self.1.clone(), wakeup)))
+            }
+            Ok(None) => {
+                self.2.register(cx.waker());
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Makes [`TcpListener::try_accept`] fail with `WouldBlock` instead of
+    /// waiting when no connection is ready.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.1
+            .coordinator
+            .lock()
+            .unwrap()
+            .listener(self.0)?
+            .nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+impl Drop for TcpListener {
+    /// Frees the port and resets every connection nobody accepted yet.
+    /// SYNs arriving afterwards are answered with a RST.
+    fn drop(&mut self) {
+        self.1.coordinator.lock().unwrap().unlisten(self.0);
+    }
+}
+
+impl TcpStream {
+    pub(crate) fn quad(&self) -> Quad {
+        self.0
+    }
+
+    /// Makes reads, writes and flushes fail with `WouldBlock` instead of
+    /// waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.with_options(|o| o.nonblocking = nonblocking)
+    }
+
+    /// Limits how long a read waits for data, `None` waits forever. Reads
+    /// that time out fail with `TimedOut`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.with_options(|o| o.read_timeout = timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.with_options(|o| o.read_timeout)
+    }
+
+    /// Limits how long a write waits for room in the send queue and a flush
+    /// for the peer's acknowledgement, `None` waits forever.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.with_options(|o| o.write_timeout = timeout)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.with_options(|o| o.write_timeout)
+    }
+
+    /// Sets what dropping the stream does with data the peer hasn't
+    /// acknowledged yet (SO_LINGER).
+    ///
+    /// With `None`, the default, the drop returns immediately and the
+    /// connection is closed in the background. With a zero duration the
+    /// connection is reset and queued data is discarded, skipping TIME-WAIT.
+    /// Any other duration makes the drop block until everything, FIN
+    /// included, is acknowledged or the duration has passed.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.with_options(|o| o.linger = linger)
+    }
+
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        self.with_options(|o| o.linger)
+    }
+
+    fn with_options<T>(&self, f: impl FnOnce(&mut tcp::Options) -> T) -> io::Result<T> {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        let conn = conn_cord.connections.get_mut(&self.0).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Connection not found, despite TcpStream being preset.",
+            )
+        })?;
+        Ok(f(&mut conn.options))
+    }
+
+    /// Resets the connection, discarding whatever is still queued in either
+    /// direction.
+    pub fn abort(self) {
+        if let Some(c) = self
+            .1
+            .coordinator
+            .lock()
+            .unwrap()
+            .connections
+            .get_mut(&self.0)
+        {
+            c.abort();
+        }
+    }
+
+    /// Shuts down the read half, the write half or both.
+    ///
+    /// Closing the write half sends a FIN once everything written so far has
+    /// been sent, while the peer can keep sending to us. Closing the read half
+    /// makes reads return 0 and drops whatever the peer still sends.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        let conn = conn_cord.connections.get_mut(&self.0).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Connection not found, despite TcpStream being preset.",
+            )
+        })?;
+        if let Shutdown::Read | Shutdown::Both = how {
+            conn.close_read();
+        }
+        if let Shutdown::Write | Shutdown::Both = how {
+            conn.close();
+        }
+        drop(conn_cord);
+        self.2.rcv.notify();
+        self.1.poll.notify();
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+impl TcpStream {
+    /// Runs `op` on the connection, registering the task with `signal` if it
+    /// has to wait.
+    fn poll_op<T>(
+        &self,
+        cx: &mut Context<'_>,
+        signal: &Signal,
+        op: impl FnOnce(&mut tcp::Connection) -> Option<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let mut conn_cord = self.1.coordinator.lock().unwrap();
+        let conn = match conn_cord.stream(&self.0) {
+            Ok(conn) => conn,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        match op(conn) {
+            Some(result) => Poll::Ready(result),
+            None => {
+                signal.register(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Resolves once the handshake of a stream from
+    /// [`Interface::start_connect`] is over.
+    pub(crate) fn poll_connect(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_op(cx, &self.2.rcv, connect_now)
+    }
+
+    pub(crate) fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_op(cx, &self.2.rcv, |conn| read_now(conn, buf))
+    }
+
+    pub(crate) fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.poll_op(cx, &self.2.send, |conn| write_now(conn, buf))
+    }
+
+    pub(crate) fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_op(cx, &self.2.send, flush_now)
+    }
+}
+
+// Like std, a zero timeout is rejected rather than meaning "don't wait".
+pub(crate) fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Cannot set a zero timeout",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::{Fault, FaultyDevice, Policy, Rule};
+
+    fn addr(interface: &Interface, port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(interface.addr(), port)
+    }
+
+    #[test]
+    fn loopback_echo() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        let mut accepted = listener.try_accept().unwrap();
+
+        stream.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        accepted.write_all(b"world").unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[test]
+    fn loopback_close_gives_eof() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        let mut accepted = listener.try_accept().unwrap();
+
+        stream.write_all(b"request").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut request = Vec::new();
+        accepted.read_to_end(&mut request).unwrap();
+        assert_eq!(request, b"request");
+
+        accepted.write_all(b"response").unwrap();
+        drop(accepted);
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"response");
+    }
+
+    #[test]
+    fn loopback_bulk_transfer() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let receiver = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            listener
+                .try_accept()
+                .unwrap()
+                .read_to_end(&mut received)
+                .unwrap();
+            received
+        });
+
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        stream.write_all(&data).unwrap();
+        drop(stream);
+        assert_eq!(receiver.join().unwrap(), data);
+    }
+
+    #[test]
+    fn loopback_connections_in_parallel() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let streams: Vec<_> = (0..4)
+            .map(|_| client.connect(addr(&server, 80)).unwrap())
+            .collect();
+        let echoes: Vec<_> = (0..4)
+            .map(|_| {
+                let mut accepted = listener.try_accept().unwrap();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 64];
+                    loop {
+                        let n = accepted.read(&mut buf).unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        accepted.write_all(&buf[..n]).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let clients: Vec<_> = streams
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut stream)| {
+                std::thread::spawn(move || {
+                    let message = format!("client {}", i);
+                    stream.write_all(message.as_bytes()).unwrap();
+                    stream.shutdown(Shutdown::Write).unwrap();
+                    let mut echoed = String::new();
+                    stream.read_to_string(&mut echoed).unwrap();
+                    assert_eq!(echoed, message);
+                })
+            })
+            .collect();
+        for thread in clients.into_iter().chain(echoes) {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn loopback_connect_refused() {
+        let (mut client, server) = Interface::loopback_pair();
+        match client.connect(addr(&server, 80)) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            Ok(_) => panic!("connected to a port nobody listens on"),
+        }
+    }
+
+    // A capture file the test can look into while the interface writes it.
+    #[derive(Clone, Default)]
+    struct SharedFile(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // The datagrams in a pcap file.
+    fn records(file: &[u8]) -> Vec<&[u8]> {
+        let mut records = Vec::new();
+        let mut rest = &file[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            records.push(&rest[16..16 + len]);
+            rest = &rest[16 + len..];
+        }
+        records
+    }
+
+    #[test]
+    fn loopback_capture() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let file = SharedFile::default();
+        server.set_capture(Some(Capture::new(file.clone()).unwrap()));
+        let ignored = SharedFile::default();
+        let mut capture = Capture::new(ignored.clone()).unwrap();
+        capture.set_filter(|local, _remote| local.port() == 81);
+        client.set_capture(Some(capture));
+
+        let listener = server.bind(80).unwrap();
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        let mut accepted = listener.try_accept().unwrap();
+        stream.write_all(b"hello").unwrap();
+        accepted.read_exact(&mut [0; 5]).unwrap();
+        server.set_capture(None);
+
+        let file = file.0.lock().unwrap();
+        let records = records(&file);
+        let packets: Vec<_> = records
+            .iter()
+            .map(|datagram| etherparse::SlicedPacket::from_ip(datagram).unwrap())
+            .collect();
+        let tcp = |i: usize| match &packets[i].transport {
+            Some(etherparse::TransportSlice::Tcp(tcp)) => tcp.clone(),
+            _ => panic!("not a TCP segment"),
+        };
+        // The handshake, from the server's point of view.
+        assert!(tcp(0).syn() && !tcp(0).ack());
+        assert_eq!(tcp(0).destination_port(), 80);
+        assert!(tcp(1).syn() && tcp(1).ack());
+        assert_eq!(tcp(1).source_port(), 80);
+        assert!(records.iter().any(|datagram| datagram.ends_with(b"hello")));
+        // Nothing matched the client's filter.
+        assert_eq!(ignored.0.lock().unwrap().len(), 24);
+    }
+
+    #[test]
+    fn dropped_datagrams_are_counted() {
+        let mut coordinator = ConnectionCoordinator::default();
+        // Room for the pcap header and nothing else.
+        let capture = Capture::new(io::Cursor::new([0; 24])).unwrap();
+        coordinator.set_capture(Some(capture));
+        let mut syn = Vec::new();
+        etherparse::PacketBuilder::ipv4([10, 0, 0, 1], LOCAL_ADDR.octets(), 64)
+            .tcp(4000, 80, 1000, 1024)
+            .syn()
+            .write(&mut syn, &[])
+            .unwrap();
+        let mut bad_checksum = syn.clone();
+        *bad_checksum.last_mut().unwrap() ^= 1;
+        let truncated = syn[..syn.len() - 1].to_vec();
+        for datagram in [vec![0x45, 0], truncated, bad_checksum] {
+            coordinator.on_datagram(&datagram, Instant::now());
+        }
+
+        let stats = Stats {
+            malformed: 1,
+            truncated: 1,
+            bad_checksum: 1,
+            capture_errors: 1,
+        };
+        assert_eq!(coordinator.stats(), stats);
+        assert!(coordinator.capture.is_none());
+    }
+
+    // Payloads of the SYNs in a capture file, to the given port.
+    fn syn_payloads(file: &SharedFile, port: u16) -> Vec<Vec<u8>> {
+        let file = file.0.lock().unwrap();
+        records(&file)
+            .into_iter()
+            .filter_map(|datagram| {
+                let packet = etherparse::SlicedPacket::from_ip(datagram).unwrap();
+                match packet.transport {
+                    Some(etherparse::TransportSlice::Tcp(tcp))
+                        if tcp.syn() && !tcp.ack() && tcp.destination_port() == port =>
+                    {
+                        Some(tcp.payload().to_vec())
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn read_string(stream: &mut TcpStream, len: usize) -> String {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn loopback_fast_open() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        server.set_fast_open(true);
+        let file = SharedFile::default();
+        server.set_capture(Some(Capture::new(file.clone()).unwrap()));
+        let listener = server.bind(80).unwrap();
+
+        // The first connection asks for a cookie, its data waits for the
+        // handshake. The ones after that send it on the SYN.
+        for data in ["first", "second", "third"] {
+            let _stream = client.connect_fast_open(addr(&server, 80), data.as_bytes());
+            let mut accepted = listener.try_accept().unwrap();
+            assert_eq!(read_string(&mut accepted, data.len()), data);
+        }
+        assert_eq!(
+            syn_payloads(&file, 80),
+            [&b""[..], b"second", b"third"].map(<[u8]>::to_vec)
+        );
+    }
+
+    #[test]
+    fn loopback_fast_open_with_a_stale_cookie() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        server.set_fast_open(true);
+        let file = SharedFile::default();
+        server.set_capture(Some(Capture::new(file.clone()).unwrap()));
+        let listener = server.bind(80).unwrap();
+        let _stream = client.connect_fast_open(addr(&server, 80), b"first");
+        listener.try_accept().unwrap();
+
+        // The cookie the client has is no longer accepted, the data goes
+        // again after the handshake and the client gets a new cookie.
+        server.rotate_fast_open_key();
+        server.rotate_fast_open_key();
+        for data in ["stale", "fresh"] {
+            let _stream = client.connect_fast_open(addr(&server, 80), data.as_bytes());
+            let mut accepted = listener.try_accept().unwrap();
+            assert_eq!(read_string(&mut accepted, data.len()), data);
+        }
+        assert_eq!(
+            syn_payloads(&file, 80),
+            [&b""[..], b"stale", b"fresh"].map(<[u8]>::to_vec)
+        );
+    }
+
+    #[test]
+    fn loopback_fast_open_beyond_the_window() {
+        let (a, b) = MemoryDevice::pair();
+        let mut client = Interface::with_addr(a, LOOPBACK_ADDRS[0]);
+        let mut server = InterfaceBuilder::new()
+            .addr(LOOPBACK_ADDRS[1], 24)
+            .recv_buffer_size(4)
+            .build_with_device(b)
+            .unwrap();
+        server.set_fast_open(true);
+        let file = SharedFile::default();
+        server.set_capture(Some(Capture::new(file.clone()).unwrap()));
+        let listener = server.bind(80).unwrap();
+
+        // Only what fits the window is taken from the SYN, the client sends
+        // the rest after the handshake and keeps its cookie.
+        for data in ["first", "hello world", "third"] {
+            let _stream = client.connect_fast_open(addr(&server, 80), data.as_bytes());
+            let mut accepted = listener.try_accept().unwrap();
+            assert_eq!(read_string(&mut accepted, data.len()), data);
+        }
+        assert_eq!(
+            syn_payloads(&file, 80),
+            [&b""[..], b"hello world", b"third"].map(<[u8]>::to_vec)
+        );
+    }
+
+    #[test]
+    fn loopback_abort_resets_the_peer() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let stream = client.connect(addr(&server, 80)).unwrap();
+        let mut accepted = listener.try_accept().unwrap();
+        stream.abort();
+        let e = accepted.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn loopback_zero_linger_resets_the_peer() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let stream = client.connect(addr(&server, 80)).unwrap();
+        let mut accepted = listener.try_accept().unwrap();
+        stream.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(stream);
+        let e = accepted.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn loopback_linger_waits_for_the_ack() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        let mut accepted = listener.try_accept().unwrap();
+        stream.set_linger(Some(Duration::from_secs(10))).unwrap();
+        stream.write_all(b"hello").unwrap();
+        let start = Instant::now();
+        drop(stream);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let mut buf = Vec::new();
+        accepted.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+    }
+
+    #[test]
+    fn loopback_linger_gives_up_at_the_deadline() {
+        let (a, b) = MemoryDevice::pair();
+        // The data never arrives, so it's never acknowledged.
+        let mut a = FaultyDevice::new(a, 1);
+        a.set_tx_policy(Policy {
+            script: vec![Rule {
+                seq: 1,
+                fault: Fault::Drop,
+                count: usize::MAX,
+            }],
+            ..Default::default()
+        });
+        let mut client = Interface::with_addr(a, LOOPBACK_ADDRS[0]);
+        let mut server = Interface::with_addr(b, LOOPBACK_ADDRS[1]);
+        let listener = server.bind(80).unwrap();
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        let _accepted = listener.try_accept().unwrap();
+        stream.set_linger(Some(Duration::from_millis(300))).unwrap();
+        stream.write_all(b"hello").unwrap();
+        let start = Instant::now();
+        drop(stream);
+        let elapsed = start.elapsed();
+        assert!(
+            (Duration::from_millis(300)..Duration::from_secs(5)).contains(&elapsed),
+            "Linger took {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn loopback_dropped_listener_resets_pending_connections() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        drop(listener);
+        let e = stream.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+
+        // The port can be bound again right away.
+        let listener = server.bind(80).unwrap();
+        let _stream = client.connect(addr(&server, 80)).unwrap();
+        listener.try_accept().unwrap();
+    }
+
+    fn error_kind<T>(result: io::Result<T>) -> io::ErrorKind {
+        match result {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => e.kind(),
+        }
+    }
+
+    #[test]
+    fn loopback_nonblocking() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(error_kind(listener.try_accept()), io::ErrorKind::WouldBlock);
+        listener.set_nonblocking(false).unwrap();
+
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        let _accepted = listener.try_accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        assert_eq!(
+            error_kind(stream.read(&mut [0; 8])),
+            io::ErrorKind::WouldBlock
+        );
+        // Writes fail instead of waiting for room in the send buffer.
+        let e = loop {
+            if let Err(e) = stream.write(&[7; 1024]) {
+                break e;
+            }
+        };
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn loopback_timeouts() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        let _accepted = listener.try_accept().unwrap();
+        let timeout = Duration::from_millis(100);
+
+        stream.set_read_timeout(Some(timeout)).unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            error_kind(stream.read(&mut [0; 8])),
+            io::ErrorKind::TimedOut
+        );
+        assert!(start.elapsed() >= timeout);
+
+        stream.set_write_timeout(Some(timeout)).unwrap();
+        let e = loop {
+            let start = Instant::now();
+            if let Err(e) = stream.write(&[7; 1024]) {
+                assert!(start.elapsed() >= timeout);
+                break e;
+            }
+        };
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+        assert_eq!(
+            error_kind(stream.set_read_timeout(Some(Duration::ZERO))),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn loopback_dropped_interface_resets_its_connections() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let listener = server.bind(80).unwrap();
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        // Closing in the background, waiting for our FIN.
+        drop(listener.try_accept().unwrap());
+        drop(listener);
+        stream.read_to_end(&mut Vec::new()).unwrap();
+
+        drop(server);
+        stream.set_write_timeout(Some(TICK)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let e = loop {
+            match stream.write(b"hello") {
+                Err(e) => break e,
+                Ok(_) if Instant::now() < deadline => std::thread::sleep(TICK),
+                Ok(_) => panic!("Connection not reset"),
+            }
+        };
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    // What `poll` reports, by token.
+    fn poll_events(interface: &mut Interface, timeout: Duration) -> Vec<(usize, Ready)> {
+        let mut events = Events::new();
+        interface.poll(&mut events, Some(timeout)).unwrap();
+        events
+            .iter()
+            .map(|event| (event.token().0, event.readiness()))
+            .collect()
+    }
+
+    #[test]
+    fn loopback_poll() {
+        let (mut client, mut server) = Interface::loopback_pair();
+        let timeout = Duration::from_millis(50);
+        let listener = server.bind(80).unwrap();
+        server
+            .register(&listener, Token(0), Ready::READABLE)
+            .unwrap();
+        let start = Instant::now();
+        assert_eq!(poll_events(&mut server, timeout), []);
+        assert!(start.elapsed() >= timeout);
+
+        let mut stream = client.connect(addr(&server, 80)).unwrap();
+        client.register(&stream, Token(0), Ready::WRITABLE).unwrap();
+        assert_eq!(poll_events(&mut client, timeout), [(0, Ready::WRITABLE)]);
+        assert_eq!(
+            poll_events(&mut server, Duration::from_secs(5)),
+            [(0, Ready::READABLE)]
+        );
+        let mut accepted = listener.try_accept().unwrap();
+        server
+            .register(&accepted, Token(1), Ready::READABLE)
+            .unwrap();
+        assert_eq!(poll_events(&mut server, timeout), []);
+
+        // Readable until read.
+        stream.write_all(b"hello").unwrap();
+        assert_eq!(
+            poll_events(&mut server, Duration::from_secs(5)),
+            [(1, Ready::READABLE)]
+        );
+        assert_eq!(poll_events(&mut server, timeout), [(1, Ready::READABLE)]);
+        accepted.read_exact(&mut [0; 5]).unwrap();
+        assert_eq!(poll_events(&mut server, timeout), []);
+
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(
+            poll_events(&mut server, Duration::from_secs(5)),
+            [(1, Ready::READABLE | Ready::HUP)]
+        );
+        stream.abort();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !poll_events(&mut server, timeout)
+            .contains(&(1, Ready::READABLE | Ready::HUP | Ready::ERROR))
+        {
+            assert!(Instant::now() < deadline, "Reset not reported");
+        }
     }
 }
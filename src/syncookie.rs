@@ -0,0 +1,163 @@
+//! SYN cookies (RFC 4987, section 3.6).
+//!
+//! Once a listener's SYN backlog is full we stop keeping state for new
+//! handshakes and encode what we need into the initial sequence number of the
+//! SYN-ACK instead:
+//!
+//! ```text
+//!  31     27 26  24 23                    0
+//! +---------+------+-----------------------+
+//! |  time   | mss  |  MAC(quad, irs, time) |
+//! +---------+------+-----------------------+
+//! ```
+//!
+//! `time` counts 64 second periods so cookies expire, `mss` indexes
+//! [`MSS_TABLE`]. The stack negotiates neither window scaling nor timestamps,
+//! so the MSS is the only option that has to survive the round trip.
+
+use std::{
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use crate::siphash;
+
+const PERIOD: Duration = Duration::from_secs(64);
+// How many periods old a cookie may be when the final ACK arrives.
+const MAX_AGE: u32 = 2;
+const MSS_TABLE: [u16; 8] = [536, 1200, 1300, 1360, 1400, 1440, 1452, 1460];
+
+pub(crate) struct SynCookies {
+    key: siphash::Key,
+    epoch: Instant,
+    // Period we last handed out a cookie in. ACKs for unknown connections
+    // are only checked against cookies while they might be outstanding.
+    last_sent: Option<u32>,
+}
+
+impl Default for SynCookies {
+    fn default() -> Self {
+        SynCookies::new(siphash::random_key(), Instant::now())
+    }
+}
+
+impl SynCookies {
+    /// The clock starts at `epoch`.
+    pub(crate) fn new(key: siphash::Key, epoch: Instant) -> Self {
+        SynCookies {
+            key,
+            epoch,
+            last_sent: None,
+        }
+    }
+
+    fn period(&self, now: Instant) -> u32 {
+        (now.saturating_duration_since(self.epoch).as_secs() / PERIOD.as_secs()) as u32
+    }
+
+    /// ISS for a SYN-ACK answering a SYN with sequence number `irs` that
+    /// advertised `mss`.
+    pub(crate) fn generate(
+        &mut self,
+        now: Instant,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        irs: u32,
+        mss: u16,
+    ) -> u32 {
+        let period = self.period(now);
+        self.last_sent = Some(period);
+        let time = period % 32;
+        // The largest MSS in the table that doesn't exceed the peer's.
+        let index = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+        time << 27 | index << 24 | self.mac(local, remote, irs, time)
+    }
+
+    /// Checks the cookie acknowledged by the final ACK of a handshake and
+    /// returns the MSS it encodes.
+    pub(crate) fn check(
+        &self,
+        now: Instant,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        irs: u32,
+        cookie: u32,
+    ) -> Option<u16> {
+        let period = self.period(now);
+        if self
+            .last_sent
+            .is_none_or(|sent| period.wrapping_sub(sent) > MAX_AGE)
+        {
+            return None;
+        }
+        let time = cookie >> 27;
+        let age = (period % 32).wrapping_sub(time) % 32;
+        if age > MAX_AGE || cookie & 0xff_ffff != self.mac(local, remote, irs, time) {
+            return None;
+        }
+        Some(MSS_TABLE[(cookie >> 24 & 0b111) as usize])
+    }
+
+    fn mac(&self, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16), irs: u32, time: u32) -> u32 {
+        let mut data = [0u8; 20];
+        data[..4].copy_from_slice(&local.0.octets());
+        data[4..6].copy_from_slice(&local.1.to_be_bytes());
+        data[6..10].copy_from_slice(&remote.0.octets());
+        data[10..12].copy_from_slice(&remote.1.to_be_bytes());
+        data[12..16].copy_from_slice(&irs.to_be_bytes());
+        data[16..].copy_from_slice(&time.to_be_bytes());
+        siphash::hash(&self.key, &data) as u32 & 0xff_ffff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 1), 80);
+    const REMOTE: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 2), 4000);
+
+    #[test]
+    fn cookies_encode_the_mss() {
+        let now = Instant::now();
+        let mut cookies = SynCookies::new([0; 16], now);
+        assert_eq!(cookies.check(now, LOCAL, REMOTE, 1000, 0), None);
+        for (mss, encoded) in [(1400, 1400), (1420, 1400), (9000, 1460), (100, 536)] {
+            let cookie = cookies.generate(now, LOCAL, REMOTE, 1000, mss);
+            assert_eq!(
+                cookies.check(now, LOCAL, REMOTE, 1000, cookie),
+                Some(encoded)
+            );
+        }
+    }
+
+    #[test]
+    fn cookies_are_bound_to_the_handshake() {
+        let now = Instant::now();
+        let mut cookies = SynCookies::new([0; 16], now);
+        let cookie = cookies.generate(now, LOCAL, REMOTE, 1000, 1400);
+        assert_eq!(cookies.check(now, LOCAL, REMOTE, 1001, cookie), None);
+        assert_eq!(
+            cookies.check(now, LOCAL, (REMOTE.0, 4001), 1000, cookie),
+            None
+        );
+        assert_eq!(cookies.check(now, LOCAL, REMOTE, 1000, cookie ^ 1), None);
+    }
+
+    #[test]
+    fn cookies_are_valid_for_a_while() {
+        let epoch = Instant::now();
+        let mut cookies = SynCookies::new([0; 16], epoch);
+        let sent = epoch + PERIOD;
+        let cookie = cookies.generate(sent, LOCAL, REMOTE, 1000, 1400);
+        for at in [sent, sent + PERIOD * MAX_AGE] {
+            assert_eq!(cookies.check(at, LOCAL, REMOTE, 1000, cookie), Some(1400));
+        }
+        // Too old, from a clock that's behind, or not ours.
+        for at in [sent + PERIOD * (MAX_AGE + 1), epoch] {
+            assert_eq!(cookies.check(at, LOCAL, REMOTE, 1000, cookie), None);
+        }
+        assert_eq!(cookies.check(sent, LOCAL, REMOTE, 1001, cookie), None);
+        assert_eq!(cookies.check(sent, LOCAL, REMOTE, 1000, cookie ^ 1), None);
+    }
+}
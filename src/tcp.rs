@@ -1,20 +1,57 @@
+//! The TCP state machine, free of any I/O.
+//!
+//! A [`Connection`] is fed parsed [`Segment`]s and the current time, and
+//! whatever it has to say in return ends up as IP datagrams in an
+//! [`Outbox`]. Reading from and writing to an actual device, as well as
+//! reading the clock, is up to whoever drives it.
+
 use std::{
+    cmp,
     collections::VecDeque,
-    io::{self, Write},
+    net::Ipv4Addr,
+    time::{Duration, Instant},
 };
 
 use bitflags::bitflags;
 use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
 
-#[derive(Debug)]
+use crate::{seq::SeqNum, siphash, tfo};
+
+// Bytes a connection buffers for sending before writes block, by default.
+const DEFAULT_SEND_BUFFER_SIZE: usize = 1024;
+// Without window scaling this is the largest window we can advertise.
+pub(crate) const MAX_RECV_BUFFER_SIZE: usize = u16::MAX as usize;
+// MSS of an Ethernet MTU minus IP and TCP headers.
+const DEFAULT_LINK_MSS: u16 = 1460;
+// IP and TCP headers without options.
+const HEADERS_LEN: usize = 40;
+// RFC 9293, section 3.7.1: what to assume when the peer doesn't say.
+const DEFAULT_SEGMENT_SIZE: u16 = 536;
+// Smallest MSS we go along with, as Linux does. Without a floor an MSS of 0
+// leaves nothing to send but empty segments.
+const MIN_SEGMENT_SIZE: u16 = 88;
+const SYN_RETRIES: u32 = 5;
+const RETRIES: u32 = 15;
+const MSL: Duration = Duration::from_secs(30);
+// How long an abandoned connection waits in FIN-WAIT-2 for the peer's FIN.
+const FIN_WAIT2_TIMEOUT: Duration = Duration::from_secs(60);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum State {
-    //Closed,
     //Listen,
+    SyncSent,
     SyncRcvd,
     Estab,
     FinWait1,
     FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
     TimeWait,
+    Closed,
 }
 
 bitflags! {
@@ -24,11 +61,40 @@ bitflags! {
     }
 }
 
+/// A received segment, its headers parsed and the payload still borrowed
+/// from the datagram.
+#[derive(Clone)]
+pub struct Segment<'a> {
+    pub ip_header: Ipv4HeaderSlice<'a>,
+    pub tcp_header: TcpHeaderSlice<'a>,
+    pub payload: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// Whether the IP and TCP checksums match the contents.
+    pub fn is_intact(&self) -> bool {
+        self.ip_header.header_checksum() == self.ip_header.to_header().calc_header_checksum()
+            && self
+                .tcp_header
+                .calc_checksum_ipv4(&self.ip_header, self.payload)
+                .is_ok_and(|checksum| checksum == self.tcp_header.checksum())
+    }
+}
+
+/// Datagrams waiting to be sent, oldest first.
+pub type Outbox = VecDeque<Vec<u8>>;
+
 impl State {
     pub fn is_synchronized(&self) -> bool {
         match self {
-            State::SyncRcvd => false,
-            State::Estab | State::FinWait1 | State::FinWait2 | State::TimeWait => true,
+            State::SyncSent | State::SyncRcvd | State::Closed => false,
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait
+            | State::Closing
+            | State::LastAck
+            | State::TimeWait => true,
         }
     }
 }
@@ -37,186 +103,837 @@ pub struct Connection {
     state: State,
     send: SendSequenceSpace,
     recv: RecvSequenceSpace,
+    timers: Timers,
+    // Largest segment the peer is willing to receive.
+    mss: usize,
+    // Largest segment the device carries, what we advertise.
+    link_mss: usize,
+    // Most bytes `unacked` and `incomming` hold.
+    pub(crate) send_buffer_size: usize,
+    recv_buffer_size: usize,
     ip_header: Ipv4Header,
     tcp_header: TcpHeader,
 
     pub(crate) incomming: VecDeque<u8>,
     pub(crate) unacked: VecDeque<u8>,
+    // The user is done writing, a FIN goes out once `unacked` has been sent.
+    closed: bool,
+    // The user is done reading, anything still arriving is dropped.
+    read_closed: bool,
+    // No stream refers to the connection anymore, it can go once closed.
+    abandoned: bool,
+    // The user asked for a reset, sent on the next tick.
+    aborted: bool,
+    pub(crate) options: Options,
+    // Sequence number our FIN occupies, once it has been sent.
+    closed_at: Option<SeqNum>,
+    reset: bool,
+    // The reset was ours, the peer stopped answering our retransmissions.
+    timed_out: bool,
+
+    // Fast Open option we put on our SYN (empty for a cookie request).
+    fast_open: Option<Vec<u8>>,
+    // Data went out on (or was accepted from) the SYN.
+    fast_open_data: bool,
+    // Cookie the server gave us, waiting to be cached by the interface.
+    fast_open_cookie: Option<Vec<u8>>,
 }
 
 impl Connection {
     pub(crate) fn is_rcv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // TODO: CLOSE-WAIT, LAST-ACK, CLOSED, CLOSING
-            true
-        } else {
-            false
+        matches!(
+            self.state,
+            State::CloseWait | State::Closing | State::LastAck | State::TimeWait | State::Closed
+        )
+    }
+
+    pub(crate) fn is_synchronized(&self) -> bool {
+        self.state.is_synchronized()
+    }
+
+    /// Closes our sending side: the FIN goes out after whatever is still
+    /// queued in `unacked`, the peer may keep sending.
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub(crate) fn is_write_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Closes our receiving side: buffered and future data is discarded
+    /// (but still acknowledged, so the peer isn't stalled).
+    pub(crate) fn close_read(&mut self) {
+        self.read_closed = true;
+        self.incomming.clear();
+    }
+
+    pub(crate) fn is_read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// The application is done with the connection: both halves are closed
+    /// and the close handshake finishes in the background.
+    pub(crate) fn abandon(&mut self) {
+        self.close();
+        self.close_read();
+        self.abandoned = true;
+    }
+
+    pub(crate) fn is_abandoned(&self) -> bool {
+        self.abandoned
+    }
+
+    /// Throws away everything queued in either direction and resets the
+    /// connection (RFC 793 ABORT). The RST goes out on the next tick.
+    pub(crate) fn abort(&mut self) {
+        self.unacked.clear();
+        self.close();
+        self.close_read();
+        self.aborted = true;
+    }
+
+    /// Whether the peer has acknowledged everything we sent including our
+    /// FIN, or the connection is gone.
+    pub(crate) fn is_write_done(&self) -> bool {
+        self.state == State::Closed || self.closed_at.is_some_and(|fin| fin < self.send.una)
+    }
+
+    pub(crate) fn is_half_open(&self) -> bool {
+        self.state == State::SyncRcvd
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// Whether data arrived on the SYN with a valid Fast Open cookie.
+    pub(crate) fn has_fast_open_data(&self) -> bool {
+        self.state == State::SyncRcvd && self.fast_open_data
+    }
+
+    pub(crate) fn is_reset(&self) -> bool {
+        self.reset
+    }
+
+    /// Whether the connection was given up on after too many
+    /// retransmissions, rather than reset by the peer.
+    pub(crate) fn is_timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    #[cfg(any(test, feature = "fuzzing"))]
+    /// SND.UNA and RCV.NXT, neither of which ever moves backwards. There's
+    /// no RCV.NXT before the peer's SYN.
+    pub(crate) fn progress(&self) -> (SeqNum, Option<SeqNum>) {
+        let rcv_nxt = (self.state != State::SyncSent).then_some(self.recv.nxt);
+        (self.send.una, rcv_nxt)
+    }
+
+    #[cfg(any(test, feature = "fuzzing"))]
+    /// Panics if the sequence variables or buffers are off, whatever the
+    /// peer sent.
+    pub(crate) fn check_invariants(&self) {
+        let flight = (self.send.nxt - self.send.una) as usize;
+        // Unacknowledged data plus SYN and FIN.
+        assert!(
+            flight <= self.unacked.len() + 2,
+            "{} bytes in flight, {} unacknowledged",
+            flight,
+            self.unacked.len()
+        );
+        assert!(self.incomming.len() <= self.recv_buffer_size);
+        assert!(
+            self.mss <= self.link_mss,
+            "MSS {} above the link's",
+            self.mss
+        );
+        if let Some(fin) = self.closed_at {
+            assert!(self.send.nxt > fin, "FIN at {} not sent", fin);
         }
     }
 
     pub fn availability(&self) -> Available {
         let mut a = Available::empty();
-        if self.is_rcv_closed() || !self.incomming.is_empty() {
+        if self.is_rcv_closed() || self.read_closed || self.reset || !self.incomming.is_empty() {
             a |= Available::READ;
         }
-        // TODO: Set available WRITE
+        // A write either fails right away or has room in the send queue.
+        if self.closed || self.reset || self.unacked.len() < self.send_buffer_size {
+            a |= Available::WRITE;
+        }
         a
     }
+
+    /// Cookie to remember for the peer after a Fast Open handshake. An empty
+    /// cookie means the one we sent was rejected and should be forgotten.
+    pub(crate) fn take_fast_open_cookie(&mut self) -> Option<Vec<u8>> {
+        self.fast_open_cookie.take()
+    }
+}
+
+/// Socket options set through the stream API.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Options {
+    pub(crate) nonblocking: bool,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    // SO_LINGER: how long dropping the stream waits for our data to be
+    // acknowledged, zero resets the connection instead.
+    pub(crate) linger: Option<Duration>,
+}
+
+/// How an interface sets up its connections.
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    // Largest segment the device carries.
+    pub(crate) link_mss: u16,
+    pub(crate) send_buffer_size: usize,
+    // At most MAX_RECV_BUFFER_SIZE, the window we advertise at most.
+    pub(crate) recv_buffer_size: usize,
+    // What streams start out with.
+    pub(crate) options: Options,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            link_mss: DEFAULT_LINK_MSS,
+            send_buffer_size: DEFAULT_SEND_BUFFER_SIZE,
+            recv_buffer_size: MAX_RECV_BUFFER_SIZE,
+            options: Options::default(),
+        }
+    }
 }
 
 struct SendSequenceSpace {
     // send unacknowledged
-    una: u32,
+    una: SeqNum,
     // send next
-    nxt: u32,
+    nxt: SeqNum,
     // send window
     wnd: u16,
-    // send urgent pointer
-    up: bool,
     // segment sequence number used for last window update
-    wl1: usize,
+    wl1: SeqNum,
     // segment acknowledgment number used for last window update
-    wl2: usize,
+    wl2: SeqNum,
     // intial send sequence number
-    iss: u32,
+    iss: SeqNum,
 }
 
 struct RecvSequenceSpace {
     // receive next
-    nxt: u32,
+    nxt: SeqNum,
     // receive window
     wnd: u16,
-    // receive urgent pointer
-    up: bool,
     // initial receive sequence number
-    irs: u32,
+    irs: SeqNum,
 }
 
-impl Connection {
-    pub fn accept(
-        nic: &mut tun_tap::Iface,
-        ip_header: Ipv4HeaderSlice,
-        tcp_header: TcpHeaderSlice,
-        payload: &[u8],
-    ) -> Result<Option<Self>, io::Error> {
-        if !tcp_header.syn() {
-            // only expected SYN packet.
-            return Ok(None);
+struct Timers {
+    // When each not yet acknowledged segment was (first) sent, for RTT
+    // samples. In the order they were sent, sequence numbers wrap.
+    send_times: VecDeque<(SeqNum, Instant)>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    retransmit_at: Option<Instant>,
+    // Consecutive retransmissions of the oldest unacknowledged segment.
+    retries: u32,
+    time_wait_until: Option<Instant>,
+    fin_wait2_until: Option<Instant>,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Timers {
+            send_times: Default::default(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            retransmit_at: None,
+            retries: 0,
+            time_wait_until: None,
+            fin_wait2_until: None,
+        }
+    }
+}
+
+impl Timers {
+    // RFC 6298, section 2.
+    fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
         }
+        self.rto = (self.srtt.unwrap() + cmp::max(self.rttvar * 4, Duration::from_millis(10)))
+            .clamp(MIN_RTO, MAX_RTO);
+    }
+}
+
+/// Initial sequence numbers as in RFC 6528: a keyed hash of the connection
+/// identifiers plus a clock ticking every 4 microseconds.
+pub(crate) struct IsnGenerator {
+    key: siphash::Key,
+    epoch: Instant,
+}
+
+impl Default for IsnGenerator {
+    fn default() -> Self {
+        IsnGenerator::new(siphash::random_key(), Instant::now())
+    }
+}
+
+impl IsnGenerator {
+    /// The clock starts at `epoch`.
+    pub(crate) fn new(key: siphash::Key, epoch: Instant) -> Self {
+        IsnGenerator { key, epoch }
+    }
 
-        let iss = 0;
-        let wnd = 1024;
-        let mut connection = Connection {
-            state: State::SyncRcvd,
+    pub(crate) fn generate(
+        &self,
+        now: Instant,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+    ) -> u32 {
+        let mut data = [0u8; 12];
+        data[..4].copy_from_slice(&local.0.octets());
+        data[4..6].copy_from_slice(&local.1.to_be_bytes());
+        data[6..10].copy_from_slice(&remote.0.octets());
+        data[10..].copy_from_slice(&remote.1.to_be_bytes());
+        let clock = (now.saturating_duration_since(self.epoch).as_micros() / 4) as u32;
+        (siphash::hash(&self.key, &data) as u32).wrapping_add(clock)
+    }
+}
+
+/// The options of a segment we make use of.
+#[derive(Default)]
+pub(crate) struct SegmentOptions<'a> {
+    pub(crate) mss: Option<u16>,
+    pub(crate) fast_open: Option<&'a [u8]>,
+}
+
+impl<'a> SegmentOptions<'a> {
+    pub(crate) fn parse(mut raw: &'a [u8]) -> Self {
+        let mut options = SegmentOptions::default();
+        while let Some(&kind) = raw.first() {
+            match kind {
+                // End of option list.
+                0 => break,
+                // No-operation.
+                1 => raw = &raw[1..],
+                _ => {
+                    let len = match raw.get(1) {
+                        Some(&len) if len >= 2 && len as usize <= raw.len() => len as usize,
+                        _ => break,
+                    };
+                    match kind {
+                        2 if len == 4 => {
+                            options.mss = Some(u16::from_be_bytes([raw[2], raw[3]]));
+                        }
+                        tfo::OPTION_KIND => options.fast_open = Some(&raw[2..len]),
+                        _ => {}
+                    }
+                    raw = &raw[len..];
+                }
+            }
+        }
+        options
+    }
+}
+
+impl Connection {
+    fn new(
+        state: State,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        iss: u32,
+        config: &Config,
+    ) -> Self {
+        let iss = SeqNum::new(iss);
+        let recv_buffer_size = cmp::min(config.recv_buffer_size, MAX_RECV_BUFFER_SIZE);
+        Connection {
+            state,
             send: SendSequenceSpace {
                 iss,
                 una: iss,
                 nxt: iss,
-                wnd,
-
-                // Not sure what those should be.
-                up: false,
-                wl1: 0,
-                wl2: 0,
+                wnd: 0,
+                wl1: SeqNum::default(),
+                wl2: SeqNum::default(),
             },
             recv: RecvSequenceSpace {
-                irs: tcp_header.sequence_number(),
-                nxt: tcp_header.sequence_number() + 1,
-                wnd: tcp_header.window_size(),
-
-                // Not sure about that one.
-                up: false,
+                irs: SeqNum::default(),
+                nxt: SeqNum::default(),
+                wnd: recv_buffer_size as u16,
             },
-            ip_header: Ipv4Header::new(
-                0,
-                64,
-                IpNumber::TCP,
-                ip_header.destination(),
-                ip_header.source(),
-            )
-            .expect("Failed to construct syn ack ip header"),
-            tcp_header: TcpHeader::new(
-                tcp_header.destination_port(),
-                tcp_header.source_port(),
-                iss,
-                wnd,
-            ),
+            timers: Timers::default(),
+            mss: DEFAULT_SEGMENT_SIZE as usize,
+            link_mss: config.link_mss as usize,
+            send_buffer_size: config.send_buffer_size,
+            recv_buffer_size,
+            ip_header: Ipv4Header::new(0, 64, IpNumber::TCP, local.0.octets(), remote.0.octets())
+                .expect("Failed to construct ip header"),
+            tcp_header: TcpHeader::new(local.1, remote.1, iss.get(), recv_buffer_size as u16),
             incomming: Default::default(),
-            unacked: Default::default()
-        };
+            unacked: Default::default(),
+            closed: false,
+            read_closed: false,
+            abandoned: false,
+            aborted: false,
+            options: config.options.clone(),
+            closed_at: None,
+            reset: false,
+            timed_out: false,
+            fast_open: None,
+            fast_open_data: false,
+            fast_open_cookie: None,
+        }
+    }
+
+    pub fn accept(
+        out: &mut Outbox,
+        now: Instant,
+        segment: &Segment,
+        iss: u32,
+        config: &Config,
+        fast_open: Option<&tfo::FastOpen>,
+    ) -> Option<Self> {
+        let Segment {
+            ip_header,
+            tcp_header,
+            payload,
+        } = segment;
+        if !tcp_header.syn() || tcp_header.ack() || tcp_header.rst() {
+            // only expected SYN packet.
+            return None;
+        }
+
+        let mut connection = Connection::new(
+            State::SyncRcvd,
+            (ip_header.destination_addr(), tcp_header.destination_port()),
+            (ip_header.source_addr(), tcp_header.source_port()),
+            iss,
+            config,
+        );
+        let irs = SeqNum::from(tcp_header.sequence_number());
+        connection.recv.irs = irs;
+        connection.recv.nxt = irs + 1;
+        connection.send.wnd = tcp_header.window_size();
+        connection.send.wl1 = irs;
+        connection.mss = cmp::min(peer_mss(tcp_header), config.link_mss) as usize;
+
+        let options = SegmentOptions::parse(tcp_header.options());
+        let fast_open = fast_open.filter(|fast_open| fast_open.enabled);
+        if let (Some(fast_open), Some(cookie)) = (fast_open, options.fast_open) {
+            let client = ip_header.source_addr();
+            if !payload.is_empty() && fast_open.is_valid(client, cookie) {
+                // RFC 7413, section 4.2.2: the data is acknowledged on the
+                // SYN-ACK and available to the application right away. What
+                // doesn't fit the window goes unacknowledged, the client
+                // sends it again after the handshake.
+                let n = cmp::min(payload.len(), connection.recv_window() as usize);
+                connection.incomming.extend(&payload[..n]);
+                connection.recv.nxt += n as u32;
+                connection.fast_open_data = true;
+            } else {
+                // Either a cookie request or a cookie we no longer recognise,
+                // in which case the data is ignored and the client will send
+                // it again after the handshake.
+                connection.fast_open = Some(fast_open.cookie(client));
+            }
+        }
 
-        //connection.tcp_header.acknowledgment_number = connection.recv.nxt;
-        connection.tcp_header.syn = true;
-        connection.tcp_header.ack = true;
-        connection.write(nic, &[])?;
-        Ok(Some(connection))
+        connection.write(out, now, connection.send.iss, 0);
+        Some(connection)
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, payload: &[u8]) -> Result<usize, io::Error> {
-        let mut buf = [0u8; 1500];
-        self.tcp_header.sequence_number = self.send.nxt;
-        self.tcp_header.acknowledgment_number = self.recv.nxt;
-        let size = std::cmp::min(
-            buf.len(),
-            self.tcp_header.header_len() + self.ip_header.header_len() + payload.len(),
+    /// Rebuilds the half-open connection a SYN cookie stood for, from the
+    /// segment completing the handshake. The segment still has to be passed to
+    /// [`Connection::on_packet`] afterwards.
+    pub(crate) fn from_syn_cookie(segment: &Segment, iss: u32, mss: u16, config: &Config) -> Self {
+        let Segment {
+            ip_header,
+            tcp_header,
+            ..
+        } = segment;
+        let mut connection = Connection::new(
+            State::SyncRcvd,
+            (ip_header.destination_addr(), tcp_header.destination_port()),
+            (ip_header.source_addr(), tcp_header.source_port()),
+            iss,
+            config,
         );
+        let irs = SeqNum::from(tcp_header.sequence_number()) - 1;
+        connection.send.nxt = connection.send.iss + 1;
+        connection.recv.irs = irs;
+        connection.recv.nxt = irs + 1;
+        connection.send.wl1 = irs;
+        connection.mss = cmp::min(mss, config.link_mss) as usize;
+        connection
+    }
+
+    /// Prepares an active open, the SYN goes out on the next tick.
+    ///
+    /// `fast_open` is the cookie to send, if any: an empty cookie asks the
+    /// server for one, a non-empty one lets `data` ride on the SYN.
+    pub fn connect(
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        iss: u32,
+        config: &Config,
+        fast_open: Option<&[u8]>,
+        data: &[u8],
+    ) -> Self {
+        let mut connection = Connection::new(State::SyncSent, local, remote, iss, config);
+        connection.unacked.extend(data);
+        connection.fast_open = fast_open.map(<[u8]>::to_vec);
+        connection.fast_open_data = !data.is_empty() && fast_open.is_some_and(|c| !c.is_empty());
+        connection
+    }
+
+    // First sequence number taken by data in `unacked`.
+    fn data_start(&self) -> SeqNum {
+        match self.state {
+            // The SYN hasn't been acknowledged yet and sits in front.
+            State::SyncSent | State::SyncRcvd => self.send.una + 1,
+            _ => self.send.una,
+        }
+    }
+
+    // Bytes in `unacked` that haven't been sent even once.
+    fn unsent(&self) -> usize {
+        let sent = (self.send.nxt - self.data_start()) as usize;
+        self.unacked.len().saturating_sub(sent)
+    }
+
+    fn recv_window(&self) -> u16 {
+        self.recv_buffer_size.saturating_sub(self.incomming.len()) as u16
+    }
+
+    fn syn_options(&self) -> Vec<u8> {
+        let mut options = vec![2, 4];
+        options.extend_from_slice(&(self.link_mss as u16).to_be_bytes());
+        if let Some(cookie) = &self.fast_open {
+            options.push(tfo::OPTION_KIND);
+            options.push(2 + cookie.len() as u8);
+            options.extend_from_slice(cookie);
+        }
+        // Pad up to a multiple of 4 bytes with end-of-option-list.
+        options.resize((options.len() + 3) & !3, 0);
+        options
+    }
+
+    /// Sends a segment starting at `seq` with up to `limit` bytes of data from
+    /// `unacked`. SYN and FIN flags are added when the segment covers them.
+    fn write(&mut self, out: &mut Outbox, now: Instant, seq: SeqNum, limit: usize) -> usize {
+        let mut buf = vec![0u8; self.link_mss + HEADERS_LEN];
+        let syn = seq == self.send.iss && matches!(self.state, State::SyncSent | State::SyncRcvd);
+        self.recv.wnd = self.recv_window();
+        self.tcp_header.sequence_number = seq.get();
+        self.tcp_header.acknowledgment_number = self.recv.nxt.get();
+        self.tcp_header.window_size = self.recv.wnd;
+        self.tcp_header.syn = syn;
+        self.tcp_header.ack = self.state != State::SyncSent;
+        self.tcp_header.rst = false;
+        let options = if syn { self.syn_options() } else { Vec::new() };
+        self.tcp_header
+            .set_options_raw(&options)
+            .expect("Failed to set tcp options");
+
+        let data_seq = seq + syn as u32;
+        let raw_offset = (data_seq - self.data_start()) as usize;
+        let offset = cmp::min(raw_offset, self.unacked.len());
+        let header_len = self.ip_header.header_len() + self.tcp_header.header_len();
+        // Options on a SYN take room from the data, the datagram still has
+        // to fit the device.
+        let data_len = cmp::min(
+            cmp::min(limit, self.unacked.len() - offset),
+            cmp::min(buf.len().saturating_sub(header_len), self.mss),
+        );
+        let size = header_len + data_len;
+        for (dst, src) in buf[header_len..size]
+            .iter_mut()
+            .zip(self.unacked.range(offset..))
+        {
+            *dst = *src;
+        }
+
+        // The FIN follows the last byte of data once the user closed.
+        let fin = self.closed
+            && raw_offset + data_len == self.unacked.len()
+            && matches!(
+                self.state,
+                State::Estab | State::CloseWait | State::FinWait1 | State::Closing | State::LastAck
+            );
+        self.tcp_header.fin = fin;
+
         self.ip_header
             .set_payload_len(size - self.ip_header.header_len())
             .expect("Failed to set ip header payload length.");
         self.tcp_header.checksum = self
             .tcp_header
-            .calc_checksum_ipv4(&self.ip_header, &[])
-            .expect("Failed to compute checksum for the syn ack response");
-
-        let mut unwritten = &mut buf[..];
+            .calc_checksum_ipv4(&self.ip_header, &buf[header_len..size])
+            .expect("Failed to compute tcp checksum");
+        let mut unwritten = &mut buf[..header_len];
         self.ip_header
             .write(&mut unwritten)
-            .expect("Failed to write ip header for syn ack");
+            .expect("Failed to write ip header");
         self.tcp_header
             .write(&mut unwritten)
-            .expect("Failed to write syn ack");
-        let payload_n = unwritten.write(payload)? as u32;
-        let unwritten = unwritten.len();
-        self.send.nxt = self.send.nxt.wrapping_add(payload_n);
+            .expect("Failed to write tcp header");
 
-        if self.tcp_header.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp_header.syn = false;
+        let mut next_seq = data_seq + data_len as u32;
+        if fin {
+            self.closed_at = Some(next_seq);
+            next_seq += 1;
+            self.state = match self.state {
+                State::Estab => State::FinWait1,
+                State::CloseWait => State::LastAck,
+                state => state,
+            };
         }
-        if self.tcp_header.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp_header.fin = false;
+        if next_seq != seq {
+            // The segment occupies sequence space and has to be acknowledged.
+            if self.send.nxt < next_seq {
+                self.send.nxt = next_seq;
+            }
+            if !self.timers.send_times.iter().any(|&(sent, _)| sent == seq) {
+                self.timers.send_times.push_back((seq, now));
+            }
+            if self.timers.retransmit_at.is_none() {
+                self.timers.retransmit_at = Some(now + self.timers.rto);
+            }
         }
-        let n = nic.send(&buf[..buf.len() - unwritten])?;
-        Ok(n)
+
+        buf.truncate(size);
+        out.push_back(buf);
+        data_len
     }
 
-    fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> Result<(), io::Error> {
-        //TODO: Fix sequence numbers
+    fn send_rst(&mut self, out: &mut Outbox, seq: SeqNum) {
         self.tcp_header.rst = true;
-        self.tcp_header.sequence_number = 0;
+        self.tcp_header.ack = false;
+        self.tcp_header.syn = false;
+        self.tcp_header.fin = false;
+        self.tcp_header.sequence_number = seq.get();
         self.tcp_header.acknowledgment_number = 0;
+        self.tcp_header
+            .set_options_raw(&[])
+            .expect("Failed to clear tcp options");
         self.ip_header
             .set_payload_len(self.tcp_header.header_len())
             .expect("Couldn't set ip header payload len.");
-        self.write(nic, &[])?;
-        Ok(())
+        self.tcp_header.checksum = self
+            .tcp_header
+            .calc_checksum_ipv4(&self.ip_header, &[])
+            .expect("Failed to compute tcp checksum");
+        let mut buf =
+            Vec::with_capacity(self.ip_header.header_len() + self.tcp_header.header_len());
+        self.ip_header
+            .write(&mut buf)
+            .expect("Failed to write ip header");
+        self.tcp_header
+            .write(&mut buf)
+            .expect("Failed to write tcp header");
+        out.push_back(buf);
     }
 
-    pub fn on_packet(
-        &mut self,
-        nic: &mut tun_tap::Iface,
-        ip_header: Ipv4HeaderSlice,
-        tcp_header: TcpHeaderSlice,
-        payload: &[u8],
-    ) -> Result<Available, io::Error> {
-        // !!!!!
-        // Not recv their FIN ACK with ack = 2.
-        // !!!!!
+    /// Drives timers: retransmissions, sending newly queued data and the FIN,
+    /// window updates and the end of TIME-WAIT.
+    pub(crate) fn on_tick(&mut self, out: &mut Outbox, now: Instant) {
+        if self.aborted && self.state != State::Closed {
+            if let State::SyncRcvd
+            | State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait = self.state
+            {
+                self.send_rst(out, self.send.nxt);
+            }
+            self.state = State::Closed;
+            return;
+        }
+        if self.abandoned && self.state == State::FinWait2 && self.timers.fin_wait2_until.is_none()
+        {
+            self.timers.fin_wait2_until = Some(now + FIN_WAIT2_TIMEOUT);
+        }
+        match self.state {
+            State::Closed => return,
+            State::TimeWait => {
+                if self.timers.time_wait_until.is_none_or(|t| now >= t) {
+                    self.state = State::Closed;
+                }
+                return;
+            }
+            // Nobody would read what the peer still has to say, don't wait
+            // for it forever.
+            State::FinWait2 if self.timers.fin_wait2_until.is_some_and(|t| now >= t) => {
+                self.state = State::Closed;
+                return;
+            }
+            State::SyncSent if self.send.nxt == self.send.iss => {
+                let syn_data = if self.fast_open_data {
+                    cmp::min(self.unacked.len(), self.mss)
+                } else {
+                    0
+                };
+                self.write(out, now, self.send.iss, syn_data);
+                return;
+            }
+            _ => {}
+        }
+
+        if self.timers.retransmit_at.is_some_and(|t| now >= t) {
+            if self.send.una == self.send.nxt {
+                self.timers.retransmit_at = None;
+                if self.send.wnd == 0 && self.unsent() > 0 {
+                    // Zero window probe.
+                    self.write(out, now, self.send.nxt, 1);
+                    return;
+                }
+            } else {
+                self.timers.retries += 1;
+                let max_retries = match self.state {
+                    State::SyncSent | State::SyncRcvd => SYN_RETRIES,
+                    _ => RETRIES,
+                };
+                if self.timers.retries > max_retries {
+                    // Give up on the peer.
+                    self.reset = true;
+                    self.timed_out = true;
+                    self.state = State::Closed;
+                    return;
+                }
+                // Karn's algorithm: no RTT samples from retransmitted segments.
+                self.timers.send_times.clear();
+                self.timers.rto = cmp::min(self.timers.rto * 2, MAX_RTO);
+                self.timers.retransmit_at = Some(now + self.timers.rto);
+                let limit = match self.state {
+                    State::SyncSent | State::SyncRcvd => 0,
+                    _ => (self.send.wnd as usize).clamp(1, self.mss),
+                };
+                self.write(out, now, self.send.una, limit);
+                return;
+            }
+        }
+
+        if self.can_send_data() {
+            let flight = self.flight();
+            let mut allowed = (self.send.wnd as usize).saturating_sub(flight);
+            if allowed == 0 && flight == 0 && self.timers.retransmit_at.is_none() {
+                // Zero window, probe it when the timer fires.
+                self.timers.retransmit_at = Some(now + self.timers.rto);
+            }
+            loop {
+                let unsent = self.unsent();
+                if (unsent == 0 || allowed == 0) && !(unsent == 0 && self.is_fin_pending()) {
+                    break;
+                }
+                let n = self.write(out, now, self.send.nxt, cmp::min(unsent, allowed));
+                allowed -= n;
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+
+        if self.is_window_update_due() {
+            self.write(out, now, self.send.nxt, 0);
+        }
+    }
+
+    /// When `on_tick` next has something to do: `now` if there's something
+    /// to send right away, otherwise the earliest timer. `None` once closed.
+    pub(crate) fn poll_at(&self, now: Instant) -> Option<Instant> {
+        match self.state {
+            State::Closed => return None,
+            _ if self.aborted => return Some(now),
+            State::TimeWait => return Some(self.timers.time_wait_until.unwrap_or(now)),
+            State::SyncSent if self.send.nxt == self.send.iss => return Some(now),
+            // The FIN-WAIT-2 timer still needs arming.
+            State::FinWait2 if self.abandoned && self.timers.fin_wait2_until.is_none() => {
+                return Some(now)
+            }
+            _ => {}
+        }
+        if self.can_send_data() {
+            let flight = self.flight();
+            let allowed = (self.send.wnd as usize).saturating_sub(flight);
+            let unsent = self.unsent();
+            if (unsent > 0 && allowed > 0)
+                || (unsent == 0 && self.is_fin_pending())
+                || (allowed == 0 && flight == 0 && self.timers.retransmit_at.is_none())
+            {
+                return Some(now);
+            }
+        }
+        if self.is_window_update_due() {
+            return Some(now);
+        }
+        let fin_wait2_until = match self.state {
+            State::FinWait2 => self.timers.fin_wait2_until,
+            _ => None,
+        };
+        match (self.timers.retransmit_at, fin_wait2_until) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn can_send_data(&self) -> bool {
+        match self.state {
+            State::Estab | State::CloseWait => true,
+            // RFC 7413, section 4.2.2: the server may respond before the
+            // handshake completes when the SYN carried data.
+            State::SyncRcvd => self.fast_open_data,
+            _ => false,
+        }
+    }
+
+    /// Bytes sent but not acknowledged yet.
+    fn flight(&self) -> usize {
+        (self.send.nxt - self.send.una) as usize
+    }
+
+    fn is_fin_pending(&self) -> bool {
+        self.closed
+            && self.closed_at.is_none()
+            && matches!(self.state, State::Estab | State::CloseWait)
+    }
+
+    /// Let the peer know once the application drained a full receive queue.
+    fn is_window_update_due(&self) -> bool {
+        self.state.is_synchronized()
+            && (self.recv.wnd as usize) < self.link_mss
+            && (self.recv_window() as usize) >= self.link_mss
+    }
+
+    pub fn on_packet(&mut self, out: &mut Outbox, now: Instant, segment: &Segment) -> Available {
+        let Segment {
+            tcp_header,
+            payload,
+            ..
+        } = segment;
+        if let State::SyncSent = self.state {
+            return self.on_syn_sent(out, now, tcp_header, payload);
+        }
+        if self.state == State::Closed || self.aborted {
+            return self.availability();
+        }
 
+        // first, check sequence number
+        //
         // RCV.NXT <= SEG.SEQ < RCV.NXT+RCV.WND
-        // RCV.NXT <= SEG.SEQ+SEG.LEN+1 < RCV.NXT.RCV.WND
-        let seqn = tcp_header.sequence_number();
+        // RCV.NXT <= SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
+        let seqn = SeqNum::from(tcp_header.sequence_number());
         let mut slen = payload.len() as u32;
         if tcp_header.fin() {
             slen += 1
@@ -224,114 +941,297 @@ impl Connection {
         if tcp_header.syn() {
             slen += 1
         };
-        let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+        self.recv.wnd = self.recv_window();
+        let wnd = self.recv.wnd as u32;
         let okay = if slen == 0 {
             // zero-length segment has separate rules for acceptance
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
+                seqn.in_window(self.recv.nxt, wnd)
             }
+        } else if self.recv.wnd == 0 {
+            false
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
-                && !is_between_wrapped(
-                    self.recv.nxt.wrapping_sub(1),
-                    seqn.wrapping_add(slen - 1),
-                    wend,
-                )
-            {
-                false
-            } else {
-                true
-            }
+            seqn.in_window(self.recv.nxt, wnd) || (seqn + (slen - 1)).in_window(self.recv.nxt, wnd)
         };
         if !okay {
-            self.write(nic, &[])?;
-            return Ok(self.availability());
+            if let State::SyncRcvd = self.state {
+                // Most likely a retransmitted SYN, our SYN-ACK got lost.
+                self.write(out, now, self.send.iss, 0);
+            } else if !tcp_header.rst() {
+                self.write(out, now, self.send.nxt, 0);
+            }
+            return self.availability();
         }
-        self.recv.nxt = seqn.wrapping_add(slen);
 
-        if !tcp_header.ack() {
-            if tcp_header.syn() {
-                self.recv.nxt = seqn.wrapping_add(1)
+        // second, check the RST bit
+        if tcp_header.rst() {
+            if seqn == self.recv.nxt {
+                self.reset = !matches!(self.state, State::TimeWait | State::LastAck);
+                self.state = State::Closed;
+            } else {
+                // RFC 5961: challenge ACK for in-window but inexact resets.
+                self.write(out, now, self.send.nxt, 0);
             }
-            return Ok(self.availability());
+            return self.availability();
+        }
+
+        // fourth, check the SYN bit
+        if tcp_header.syn() {
+            // RFC 5961: challenge ACK, the peer resets if it really restarted.
+            self.write(out, now, self.send.nxt, 0);
+            return self.availability();
         }
 
-        let ackn = tcp_header.acknowledgment_number();
+        // fifth, check the ACK field
+        if !tcp_header.ack() {
+            return self.availability();
+        }
+        let ackn = SeqNum::from(tcp_header.acknowledgment_number());
         if let State::SyncRcvd = self.state {
-            if is_between_wrapped(
-                self.send.una.wrapping_sub(1),
-                ackn,
-                self.send.nxt.wrapping_add(1),
-            ) {
+            if ackn.in_range(self.send.una + 1, self.send.nxt + 1) {
+                self.on_ack(ackn, now);
                 self.state = State::Estab;
+                self.send.wnd = tcp_header.window_size();
+                self.send.wl1 = seqn;
+                self.send.wl2 = ackn;
             } else {
-                // TODO: RST
+                self.send_rst(out, ackn);
+                return self.availability();
             }
         }
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            println!("got here");
-            if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
-                println!(
-                    "ack for {} (last: {}); prune in {:?}",
-                    ackn, self.send.una, self.unacked
-                );
+        if ackn.in_range(self.send.una + 1, self.send.nxt + 1) {
+            self.on_ack(ackn, now);
+        } else if self.send.nxt < ackn {
+            // Acknowledges something we never sent.
+            self.write(out, now, self.send.nxt, 0);
+            return self.availability();
+        }
+        if self.send.wl1 < seqn || (self.send.wl1 == seqn && ackn >= self.send.wl2) {
+            self.send.wnd = tcp_header.window_size();
+            self.send.wl1 = seqn;
+            self.send.wl2 = ackn;
+        }
+
+        let fin_acked = self.closed_at.is_some_and(|fin| fin < self.send.una);
+        match self.state {
+            State::FinWait1 if fin_acked => self.state = State::FinWait2,
+            State::Closing if fin_acked => self.enter_time_wait(now),
+            State::LastAck if fin_acked => {
+                self.state = State::Closed;
+                return self.availability();
+            }
+            _ => {}
+        }
 
-                self.send.una = ackn; 
+        // seventh, process the segment text
+        let mut needs_ack = false;
+        if !payload.is_empty() {
+            if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+                // Only in-order data is kept, anything past a hole gets
+                // retransmitted by the peer.
+                let seen = (self.recv.nxt - seqn) as usize;
+                if seqn <= self.recv.nxt && seen < payload.len() {
+                    let fresh = &payload[seen..];
+                    let n = cmp::min(fresh.len(), self.recv.wnd as usize);
+                    if !self.read_closed {
+                        self.incomming.extend(&fresh[..n]);
+                    }
+                    self.recv.nxt += n as u32;
+                }
             }
-            assert!(payload.is_empty());
+            needs_ack = true;
+        }
 
-            // Terminate the connection
-            if let State::Estab = self.state {
-                self.tcp_header.fin = true;
-                self.write(nic, &[])?;
-                self.state = State::FinWait1;
+        // eighth, check the FIN bit
+        if tcp_header.fin() && seqn + payload.len() as u32 == self.recv.nxt && !self.is_rcv_closed()
+        {
+            self.recv.nxt += 1;
+            match self.state {
+                State::SyncRcvd | State::Estab => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+            needs_ack = true;
+        } else if tcp_header.fin() && self.state == State::TimeWait {
+            // Our ACK of their FIN got lost, restart the 2 MSL timeout.
+            self.enter_time_wait(now);
+            needs_ack = true;
+        }
+
+        if needs_ack {
+            self.write(out, now, self.send.nxt, 0);
+        }
+        self.availability()
+    }
+
+    fn on_syn_sent(
+        &mut self,
+        out: &mut Outbox,
+        now: Instant,
+        tcp_header: &TcpHeaderSlice,
+        payload: &[u8],
+    ) -> Available {
+        let ackn = SeqNum::from(tcp_header.acknowledgment_number());
+        let ack_ok = tcp_header.ack() && ackn.in_range(self.send.iss + 1, self.send.nxt + 1);
+        if tcp_header.ack() && !ack_ok {
+            if !tcp_header.rst() {
+                self.send_rst(out, ackn);
+            }
+            return self.availability();
+        }
+        if tcp_header.rst() {
+            if ack_ok {
+                // Connection refused.
+                self.reset = true;
+                self.state = State::Closed;
             }
+            return self.availability();
+        }
+        if !tcp_header.syn() {
+            return self.availability();
         }
 
-        if let State::FinWait1 = self.state {
-            if self.send.una == self.send.iss + 2 {
-                // Our FIN has been ACKed.
-                println!("THEY ACKED OUR FIN");
-                self.state = State::FinWait2;
+        let seqn = SeqNum::from(tcp_header.sequence_number());
+        self.recv.irs = seqn;
+        self.recv.nxt = seqn + 1;
+        self.send.wnd = tcp_header.window_size();
+        self.send.wl1 = seqn;
+        self.send.wl2 = ackn;
+        self.mss = cmp::min(peer_mss(tcp_header) as usize, self.link_mss);
+        if let Some(cookie) = SegmentOptions::parse(tcp_header.options()).fast_open {
+            if !cookie.is_empty() {
+                self.fast_open_cookie = Some(cookie.to_vec());
             }
         }
 
+        if !ack_ok {
+            // Simultaneous open, answer with a SYN-ACK.
+            self.state = State::SyncRcvd;
+            self.write(out, now, self.send.iss, 0);
+            return self.availability();
+        }
+
+        self.on_ack(ackn, now);
+        self.state = State::Estab;
+        if self.send.nxt != self.send.una {
+            // Not all that rode along with the SYN was acknowledged, the rest
+            // goes again without waiting for the retransmission timer.
+            self.send.nxt = self.send.una;
+            self.timers.retransmit_at = None;
+            if ackn == self.send.iss + 1 && self.fast_open_cookie.is_none() {
+                // None of it was, the server didn't take our cookie.
+                self.fast_open_cookie = Some(Vec::new());
+            }
+        }
+        if !payload.is_empty() {
+            let n = cmp::min(payload.len(), self.recv_window() as usize);
+            self.incomming.extend(&payload[..n]);
+            self.recv.nxt += n as u32;
+        }
         if tcp_header.fin() {
-            match self.state {
-                State::FinWait2 => {
-                    println!("THEY HAVE CLOSED");
-                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
-                    self.write(nic, &[])?;
-                    self.state = State::TimeWait;
-                }
-                _ => unimplemented!(),
+            self.recv.nxt += 1;
+            self.state = State::CloseWait;
+        }
+        self.write(out, now, self.send.nxt, 0);
+        self.availability()
+    }
+
+    // Takes everything up to `ackn` off the retransmission queue.
+    fn on_ack(&mut self, ackn: SeqNum, now: Instant) {
+        let data_start = self.data_start();
+        if data_start < ackn {
+            let acked = cmp::min((ackn - data_start) as usize, self.unacked.len());
+            self.unacked.drain(..acked);
+        }
+
+        let mut sample = None;
+        self.timers.send_times.retain(|&(seq, sent)| {
+            let acked = seq < ackn;
+            if acked {
+                sample = Some(now - sent);
             }
+            !acked
+        });
+        if let Some(rtt) = sample {
+            self.timers.on_rtt_sample(rtt);
+        }
+
+        self.send.una = ackn;
+        self.timers.retries = 0;
+        self.timers.retransmit_at = if self.send.una == self.send.nxt {
+            None
+        } else {
+            Some(now + self.timers.rto)
+        };
+    }
+
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.state = State::TimeWait;
+        self.timers.retransmit_at = None;
+        self.timers.time_wait_until = Some(now + 2 * MSL);
+    }
+}
+
+/// Answers a segment no connection or listener wants with a RST, as
+/// RFC 793 prescribes for the CLOSED state. RSTs are never answered.
+pub(crate) fn send_reset(out: &mut Outbox, segment: &Segment) {
+    let Segment {
+        ip_header,
+        tcp_header,
+        payload,
+    } = segment;
+    if tcp_header.rst() {
+        return;
+    }
+    let mut tcp = TcpHeader::new(
+        tcp_header.destination_port(),
+        tcp_header.source_port(),
+        0,
+        0,
+    );
+    tcp.rst = true;
+    if tcp_header.ack() {
+        tcp.sequence_number = tcp_header.acknowledgment_number();
+    } else {
+        let mut slen = payload.len() as u32;
+        if tcp_header.syn() {
+            slen += 1;
+        }
+        if tcp_header.fin() {
+            slen += 1;
         }
-        Ok(self.availability())
+        tcp.ack = true;
+        tcp.acknowledgment_number = (SeqNum::from(tcp_header.sequence_number()) + slen).get();
     }
+    let ip = Ipv4Header::new(
+        tcp.header_len() as u16,
+        64,
+        IpNumber::TCP,
+        ip_header.destination(),
+        ip_header.source(),
+    )
+    .expect("Failed to construct ip header");
+    tcp.checksum = tcp
+        .calc_checksum_ipv4(&ip, &[])
+        .expect("Failed to compute tcp checksum");
+    let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len());
+    ip.write(&mut buf).expect("Failed to write ip header");
+    tcp.write(&mut buf).expect("Failed to write tcp header");
+    out.push_back(buf);
 }
 
-fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
-    // From RFC1323:
-    //     TCP determines if a data segment is "old" or "new" by testing
-    //     whether its sequence number is within 2**31 bytes of the left edge
-    //     of the window, and if it is not, discarding the data as "old".  To
-    //     ensure that new data is never mistakenly considered old and vice-
-    //     versa, the left edge of the sender's window has to be at most
-    //     2**31 away from the right edge of the receiver's window.
-    lhs.wrapping_sub(rhs) > (1 << 31)
+/// MSS that fits a device with the given MTU.
+pub(crate) fn mss_for_mtu(mtu: usize) -> u16 {
+    cmp::min(mtu.saturating_sub(HEADERS_LEN), u16::MAX as usize) as u16
 }
 
-fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
-    wrapping_lt(start, x) && wrapping_lt(x, end)
+/// MSS the sender of a SYN asked for.
+pub(crate) fn peer_mss(tcp_header: &TcpHeaderSlice) -> u16 {
+    let mss = SegmentOptions::parse(tcp_header.options())
+        .mss
+        .unwrap_or(DEFAULT_SEGMENT_SIZE);
+    cmp::max(mss, MIN_SEGMENT_SIZE)
 }
@@ -0,0 +1,119 @@
+//! TCP Fast Open (RFC 7413) cookie handling.
+//!
+//! The server side hands out cookies derived from the client address with a
+//! per-`Interface` secret, and accepts data carried on a SYN only when it comes
+//! with a cookie we issued. The client side remembers the cookies servers gave
+//! us so later connections can put their first bytes into the SYN.
+
+use std::{collections::HashMap, net::Ipv4Addr};
+
+use crate::siphash;
+
+/// TCP option kind assigned to Fast Open.
+pub(crate) const OPTION_KIND: u8 = 34;
+const COOKIE_LEN: usize = 8;
+const MIN_COOKIE_LEN: usize = 4;
+const MAX_COOKIE_LEN: usize = 16;
+
+pub(crate) struct FastOpen {
+    pub(crate) enabled: bool,
+    key: siphash::Key,
+    // Cookies minted with the previous key stay valid for one rotation so
+    // clients don't all fall back to a full handshake at once.
+    previous_key: Option<siphash::Key>,
+    cache: HashMap<Ipv4Addr, Vec<u8>>,
+}
+
+impl Default for FastOpen {
+    fn default() -> Self {
+        FastOpen::new(siphash::random_key())
+    }
+}
+
+impl FastOpen {
+    pub(crate) fn new(key: siphash::Key) -> Self {
+        FastOpen {
+            enabled: false,
+            key,
+            previous_key: None,
+            cache: Default::default(),
+        }
+    }
+
+    pub(crate) fn cookie(&self, client: Ipv4Addr) -> Vec<u8> {
+        cookie_for(&self.key, client)
+    }
+
+    pub(crate) fn is_valid(&self, client: Ipv4Addr, cookie: &[u8]) -> bool {
+        cookie == cookie_for(&self.key, client).as_slice()
+            || self
+                .previous_key
+                .is_some_and(|key| cookie == cookie_for(&key, client).as_slice())
+    }
+
+    pub(crate) fn rotate_key(&mut self, key: siphash::Key) {
+        self.previous_key = Some(std::mem::replace(&mut self.key, key));
+    }
+
+    pub(crate) fn cached_cookie(&self, server: Ipv4Addr) -> Option<&[u8]> {
+        self.cache.get(&server).map(Vec::as_slice)
+    }
+
+    pub(crate) fn cache_cookie(&mut self, server: Ipv4Addr, cookie: Vec<u8>) {
+        if is_well_formed(&cookie) {
+            self.cache.insert(server, cookie);
+        }
+    }
+
+    pub(crate) fn forget_cookie(&mut self, server: Ipv4Addr) {
+        self.cache.remove(&server);
+    }
+}
+
+fn cookie_for(key: &siphash::Key, client: Ipv4Addr) -> Vec<u8> {
+    siphash::hash(key, &client.octets()).to_be_bytes()[..COOKIE_LEN].to_vec()
+}
+
+pub(crate) fn is_well_formed(cookie: &[u8]) -> bool {
+    (MIN_COOKIE_LEN..=MAX_COOKIE_LEN).contains(&cookie.len()) && cookie.len().is_multiple_of(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+    #[test]
+    fn cookies_are_bound_to_the_client() {
+        let fast_open = FastOpen::default();
+        let cookie = fast_open.cookie(CLIENT);
+        assert!(is_well_formed(&cookie));
+        assert!(fast_open.is_valid(CLIENT, &cookie));
+        assert!(!fast_open.is_valid(Ipv4Addr::new(192, 168, 0, 3), &cookie));
+        assert!(!fast_open.is_valid(CLIENT, &cookie[..4]));
+    }
+
+    #[test]
+    fn cookies_survive_one_rotation() {
+        let mut fast_open = FastOpen::default();
+        let cookie = fast_open.cookie(CLIENT);
+        fast_open.rotate_key([1; 16]);
+        assert!(fast_open.is_valid(CLIENT, &cookie));
+        fast_open.rotate_key([2; 16]);
+        assert!(!fast_open.is_valid(CLIENT, &cookie));
+    }
+
+    #[test]
+    fn only_well_formed_cookies_are_cached() {
+        let mut fast_open = FastOpen::default();
+        fast_open.cache_cookie(CLIENT, vec![0; 3]);
+        assert_eq!(fast_open.cached_cookie(CLIENT), None);
+        fast_open.cache_cookie(CLIENT, vec![0; 18]);
+        assert_eq!(fast_open.cached_cookie(CLIENT), None);
+        fast_open.cache_cookie(CLIENT, vec![7; 8]);
+        assert_eq!(fast_open.cached_cookie(CLIENT), Some(&[7; 8][..]));
+        fast_open.forget_cookie(CLIENT);
+        assert_eq!(fast_open.cached_cookie(CLIENT), None);
+    }
+}
@@ -0,0 +1,281 @@
+//! Tests against the Linux kernel's TCP, over a real tun device.
+//!
+//! Each test moves its thread into a network namespace of its own, has an
+//! [`InterfaceBuilder`] create `tun0` there with the kernel at 10.0.0.1 and
+//! the stack at 10.0.0.2, and has `std::net` sockets talk to the stack.
+//! Nothing is left behind on the host: the namespace, and the device with
+//! it, go away with the test.
+//!
+//! Creating namespaces and devices takes CAP_SYS_ADMIN and CAP_NET_ADMIN.
+//! The lossy tests also need `tc` and the sch_netem module, so they only run
+//! when asked for:
+//!
+//! ```text
+//! sudo -E cargo test --features interop --test interop
+//! unshare -rn cargo test --features interop --test interop -- --include-ignored
+//! ```
+
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use user_space_tcp::{
+    fault::{FaultyDevice, Policy},
+    stream, Interface, InterfaceBuilder, NetDevice, TunDevice,
+};
+
+const DEVICE: &str = "tun0";
+const KERNEL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const STACK_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+// Long enough for netem losses, short enough for a hung test to fail.
+const TIMEOUT: Duration = Duration::from_secs(30);
+// Enough for a transfer to the kernel to keep a few segments in flight,
+// rather than stalling on its delayed ACKs.
+const SEND_BUFFER_SIZE: usize = 16 << 10;
+const BULK_LEN: usize = 1 << 20;
+// Less over lossy links, see setup_lossy.
+const LOSSY_BULK_LEN: usize = 256 << 10;
+
+// Moves the calling thread, and whatever it spawns, into a new network
+// namespace with `tun0` up and addressed. The stack runs on top of `wrap`
+// applied to the device.
+fn setup_with<D: NetDevice>(wrap: impl FnOnce(TunDevice) -> D) -> Interface {
+    if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+        panic!(
+            "Can't create a network namespace ({}), the interop tests need \
+             CAP_SYS_ADMIN and CAP_NET_ADMIN",
+            io::Error::last_os_error()
+        );
+    }
+    let builder = InterfaceBuilder::new()
+        .device_name(DEVICE)
+        .addr(STACK_ADDR, 24)
+        .host_addr(KERNEL_ADDR)
+        .send_buffer_size(SEND_BUFFER_SIZE);
+    let device = builder.open_tun().expect("Failed to set up tun0");
+    builder.build_with_device(wrap(device)).unwrap()
+}
+
+fn setup() -> Interface {
+    setup_with(|device| device)
+}
+
+fn run(program: &str, args: &[&str]) {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run {}: {}", program, e));
+    assert!(status.success(), "{} {:?} failed", program, args);
+}
+
+fn kernel_connect(port: u16) -> TcpStream {
+    let addr = SocketAddr::from((STACK_ADDR, port));
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT).expect("Kernel failed to connect");
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.set_write_timeout(Some(TIMEOUT)).unwrap();
+    stream
+}
+
+fn kernel_listen() -> TcpListener {
+    TcpListener::bind((KERNEL_ADDR, 0)).expect("Kernel failed to listen")
+}
+
+fn stack_connect(interface: &mut Interface, port: u16) -> stream::TcpStream {
+    let stream = interface
+        .connect(SocketAddrV4::new(KERNEL_ADDR, port))
+        .expect("Stack failed to connect");
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+// Has `send` write `len` bytes and close on a thread of its own, and checks
+// they all come out of `reader` before the FIN.
+fn transfer(send: impl FnOnce(&[u8]) + Send + 'static, mut reader: impl Read, len: usize) {
+    let sender = thread::spawn(move || send(&pattern(len)));
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).unwrap();
+    sender.join().unwrap();
+    assert_eq!(received.len(), len);
+    assert!(received == pattern(len), "Data got mangled on the way");
+}
+
+fn stack_sends(mut stack: stream::TcpStream) -> impl FnOnce(&[u8]) + Send {
+    move |data| {
+        stack.write_all(data).unwrap();
+        stack.shutdown(Shutdown::Write).unwrap();
+    }
+}
+
+fn kernel_sends(mut kernel: TcpStream) -> impl FnOnce(&[u8]) + Send {
+    move |data| {
+        kernel.write_all(data).unwrap();
+        kernel.shutdown(Shutdown::Write).unwrap();
+    }
+}
+
+#[test]
+fn kernel_connects_to_the_stack() {
+    let mut interface = setup();
+    let listener = interface.bind(7000).unwrap();
+    let mut kernel = kernel_connect(7000);
+    let mut stack = listener.try_accept().unwrap();
+
+    kernel.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    stack.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    stack.write_all(b"pong").unwrap();
+    kernel.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[test]
+fn stack_connects_to_the_kernel() {
+    let mut interface = setup();
+    let listener = kernel_listen();
+    let port = listener.local_addr().unwrap().port();
+    let mut stack = stack_connect(&mut interface, port);
+    let (mut kernel, peer) = listener.accept().unwrap();
+    assert_eq!(peer.ip(), STACK_ADDR);
+
+    stack.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    kernel.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[test]
+fn refused_by_the_kernel() {
+    let mut interface = setup();
+    // Bound but not listening, so nothing else grabs the port.
+    let socket = std::net::UdpSocket::bind((KERNEL_ADDR, 0)).unwrap();
+    let port = socket.local_addr().unwrap().port();
+    match interface.connect(SocketAddrV4::new(KERNEL_ADDR, port)) {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+        Ok(_) => panic!("Connected to a closed port"),
+    }
+}
+
+#[test]
+fn refused_by_the_stack() {
+    let _interface = setup();
+    let err =
+        TcpStream::connect_timeout(&SocketAddr::from((STACK_ADDR, 7000)), TIMEOUT).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn bulk_to_the_kernel() {
+    let mut interface = setup();
+    let listener = kernel_listen();
+    let stack = stack_connect(&mut interface, listener.local_addr().unwrap().port());
+    let (kernel, _) = listener.accept().unwrap();
+    kernel.set_read_timeout(Some(TIMEOUT)).unwrap();
+    transfer(stack_sends(stack), kernel, BULK_LEN);
+}
+
+#[test]
+fn bulk_from_the_kernel() {
+    let mut interface = setup();
+    let listener = interface.bind(7000).unwrap();
+    let kernel = kernel_connect(7000);
+    let stack = listener.try_accept().unwrap();
+    stack.set_read_timeout(Some(TIMEOUT)).unwrap();
+    transfer(kernel_sends(kernel), stack, BULK_LEN);
+}
+
+#[test]
+fn half_close() {
+    let mut interface = setup();
+    let listener = interface.bind(7000).unwrap();
+    let mut kernel = kernel_connect(7000);
+    let mut stack = listener.try_accept().unwrap();
+
+    // The kernel is done sending, the stack still answers.
+    kernel.write_all(b"request").unwrap();
+    kernel.shutdown(Shutdown::Write).unwrap();
+    let mut request = Vec::new();
+    stack.read_to_end(&mut request).unwrap();
+    assert_eq!(request, b"request");
+    stack.write_all(b"response").unwrap();
+    stack.shutdown(Shutdown::Write).unwrap();
+
+    let mut response = Vec::new();
+    kernel.read_to_end(&mut response).unwrap();
+    assert_eq!(response, b"response");
+}
+
+#[test]
+fn stack_resets() {
+    let mut interface = setup();
+    let listener = interface.bind(7000).unwrap();
+    let mut kernel = kernel_connect(7000);
+    let stack = listener.try_accept().unwrap();
+    stack.abort();
+    let err = kernel.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn kernel_resets() {
+    let mut interface = setup();
+    let listener = kernel_listen();
+    let mut stack = stack_connect(&mut interface, listener.local_addr().unwrap().port());
+    let (kernel, _) = listener.accept().unwrap();
+    // Closing with unread data makes the kernel reset the connection.
+    stack.write_all(b"unread").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    drop(kernel);
+    let err = stack.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
+// The kernel's datagrams are dropped by netem on their way out of tun0,
+// the stack's by a FaultyDevice. The stack throws away segments past a
+// hole, so losing two in the same window costs the kernel a retransmission
+// timeout, backed off: keep the losses rare enough for the test to finish.
+fn setup_lossy() -> Interface {
+    let interface = setup_with(|device| {
+        let mut device = FaultyDevice::new(device, 48);
+        device.set_tx_policy(Policy {
+            drop: 0.01,
+            ..Default::default()
+        });
+        device
+    });
+    run(
+        "tc",
+        &["qdisc", "add", "dev", DEVICE, "root", "netem", "loss", "1%"],
+    );
+    interface
+}
+
+#[test]
+#[ignore = "needs sch_netem"]
+fn lossy_bulk_to_the_kernel() {
+    let mut interface = setup_lossy();
+    let listener = kernel_listen();
+    let stack = stack_connect(&mut interface, listener.local_addr().unwrap().port());
+    let (kernel, _) = listener.accept().unwrap();
+    kernel.set_read_timeout(Some(TIMEOUT)).unwrap();
+    transfer(stack_sends(stack), kernel, LOSSY_BULK_LEN);
+}
+
+#[test]
+#[ignore = "needs sch_netem"]
+fn lossy_bulk_from_the_kernel() {
+    let mut interface = setup_lossy();
+    let listener = interface.bind(7000).unwrap();
+    let kernel = kernel_connect(7000);
+    let stack = listener.try_accept().unwrap();
+    stack.set_read_timeout(Some(TIMEOUT)).unwrap();
+    transfer(kernel_sends(kernel), stack, LOSSY_BULK_LEN);
+}